@@ -0,0 +1,94 @@
+//! Audit trail of clients authenticating, identifying and resuming.
+//!
+//! Every attempt is logged as an event with the `audit` target, including the
+//! client's IP, the SHA-256 hash of the token it used and the outcome. If
+//! `audit_log` is set, these events are written as JSON lines to that file
+//! instead of the regular logs.
+use ring::digest;
+use tracing::{info, warn, Subscriber};
+use tracing_subscriber::{filter::filter_fn, registry::LookupSpan, Layer};
+use twilight_model::gateway::Intents;
+
+use std::{
+    fmt::Write,
+    fs::{File, OpenOptions},
+    io::Result,
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use crate::model::{IdentifyInfo, ResumeInfo};
+
+/// Target of the audit events.
+pub const TARGET: &str = "audit";
+
+/// Build a layer that appends the audit events to the file at `path`.
+pub fn layer<S>(path: &str) -> Result<impl Layer<S>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let file: Arc<File> = Arc::new(OpenOptions::new().create(true).append(true).open(path)?);
+
+    Ok(tracing_subscriber::fmt::layer()
+        .json()
+        .with_writer(file)
+        .with_filter(filter_fn(|metadata| metadata.target() == TARGET)))
+}
+
+/// Hex-encoded SHA-256 hash of a token, as configured in `auth.tokens`.
+pub fn hash_token(token: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, token.as_bytes());
+
+    hash.as_ref().iter().fold(String::new(), |mut hex, byte| {
+        let _res = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+/// Log an attempt to authenticate when upgrading to a WebSocket connection.
+pub fn authentication(addr: SocketAddr, token: Option<&str>, accepted: bool) {
+    let token_hash = token.map(hash_token);
+
+    if accepted {
+        info!(target: TARGET, ip = %addr.ip(), token_hash = token_hash.as_deref(), outcome = "accepted", "Client authenticated");
+    } else {
+        warn!(target: TARGET, ip = %addr.ip(), token_hash = token_hash.as_deref(), outcome = "rejected", "Client failed to authenticate");
+    }
+}
+
+/// Hash of a token sent in IDENTIFY or RESUME.
+fn hash_gateway_token(token: &str) -> Option<String> {
+    // Discord tokens may be prefixed by 'Bot ' in IDENTIFY and RESUME
+    token.split_whitespace().last().map(hash_token)
+}
+
+/// Log an IDENTIFY, which was rejected if there is a reason.
+pub fn identify(addr: SocketAddr, identify: &IdentifyInfo, rejection: Option<&str>) {
+    let token_hash = hash_gateway_token(&identify.token);
+    let intents = identify.intents.as_ref().map(Intents::bits);
+    let [shard_id, shard_count] = identify.shard;
+
+    if let Some(reason) = rejection {
+        warn!(target: TARGET, ip = %addr.ip(), token_hash = token_hash.as_deref(), intents, shard_id, shard_count, outcome = "rejected", reason, "Client identify rejected");
+    } else {
+        info!(target: TARGET, ip = %addr.ip(), token_hash = token_hash.as_deref(), intents, shard_id, shard_count, outcome = "accepted", "Client identified");
+    }
+}
+
+/// Log a RESUME, which was rejected if there is a reason.
+pub fn resume(addr: SocketAddr, resume: &ResumeInfo, rejection: Option<&str>) {
+    let token_hash = hash_gateway_token(&resume.token);
+    let session_id = resume.session_id.as_str();
+
+    if let Some(reason) = rejection {
+        warn!(target: TARGET, ip = %addr.ip(), token_hash = token_hash.as_deref(), session_id, outcome = "rejected", reason, "Client resume rejected");
+    } else {
+        info!(target: TARGET, ip = %addr.ip(), token_hash = token_hash.as_deref(), session_id, outcome = "accepted", "Client resumed");
+    }
+}
+
+/// Log an IDENTIFY or RESUME that could not be parsed, so nothing about the
+/// client is known except for its address.
+pub fn invalid_payload(addr: SocketAddr, op: &str) {
+    warn!(target: TARGET, ip = %addr.ip(), op, outcome = "rejected", reason = "invalid_payload", "Client sent an invalid payload");
+}
@@ -1,29 +1,44 @@
 #[cfg(feature = "simd-json")]
 use halfbrown::hashmap;
-use serde::Serialize;
+use serde::{ser::Error as _, Serialize, Serializer};
 #[cfg(not(feature = "simd-json"))]
-use serde_json::Value as OwnedValue;
+use serde_json::{to_value, Value as OwnedValue};
 #[cfg(feature = "simd-json")]
-use simd_json::OwnedValue;
-use twilight_cache_inmemory::{InMemoryCache, InMemoryCacheStats, UpdateCache};
+use simd_json::{serde::to_owned_value as to_value, Mutable, OwnedValue, ValueAccess};
+use twilight_cache_inmemory::{
+    model::CachedGuild, InMemoryCache, InMemoryCacheStats, ResourceType, UpdateCache,
+};
 use twilight_model::{
     channel::{message::Sticker, Channel, StageInstance},
     gateway::{
-        payload::incoming::{GuildCreate, GuildDelete},
+        event::Event as GatewayEvent,
+        payload::incoming::GuildDelete,
         presence::{Presence, UserOrId},
-        OpCode,
+        Intents, OpCode,
+    },
+    guild::{
+        auto_moderation::AutoModerationRule, scheduled_event::GuildScheduledEvent, Emoji, Guild,
+        Member, Role,
     },
-    guild::{Emoji, Guild, Member, Role},
     id::{
-        marker::{GuildMarker, UserMarker},
+        marker::{
+            AutoModerationRuleMarker, ChannelMarker, GuildMarker, ScheduledEventMarker, UserMarker,
+        },
         Id,
     },
     voice::VoiceState,
 };
 
-use std::sync::Arc;
+use std::{
+    collections::{BTreeMap, HashMap},
+    iter,
+    sync::{Arc, Mutex, RwLock},
+};
 
-use crate::model::JsonObject;
+use crate::{
+    config::CONFIG,
+    model::{GuildExtras, JsonObject, VoiceChannelStatus},
+};
 
 #[derive(Serialize)]
 pub struct Payload {
@@ -41,26 +56,580 @@ pub enum Event {
     GuildDelete(GuildDelete),
 }
 
-pub struct Guilds(Arc<InMemoryCache>, u32);
+/// A GUILD_CREATE with the fields that twilight's [`Guild`] does not model.
+#[derive(Clone)]
+pub struct GuildCreate {
+    pub guild: Guild,
+    pub guild_scheduled_events: Vec<GuildScheduledEvent>,
+    pub auto_moderation_rules: Vec<AutoModerationRule>,
+    /// Statuses of the guild's voice channels, which twilight's [`Channel`]
+    /// does not model either.
+    pub voice_channel_statuses: HashMap<Id<ChannelMarker>, String>,
+}
+
+/// The fields of a [`GuildCreate`] that serialize as they are.
+#[derive(Serialize)]
+struct GuildCreateFields<'a> {
+    #[serde(flatten)]
+    guild: &'a Guild,
+    guild_scheduled_events: &'a [GuildScheduledEvent],
+    auto_moderation_rules: &'a [AutoModerationRule],
+}
+
+impl Serialize for GuildCreate {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = GuildCreateFields {
+            guild: &self.guild,
+            guild_scheduled_events: &self.guild_scheduled_events,
+            auto_moderation_rules: &self.auto_moderation_rules,
+        };
+
+        if self.voice_channel_statuses.is_empty() {
+            return fields.serialize(serializer);
+        }
+
+        // Only guilds with statuses pay for adding them to the serialized channels
+        let mut guild = to_value(&fields).map_err(S::Error::custom)?;
+
+        if let Some(channels) = guild.get_mut("channels").and_then(OwnedValue::as_array_mut) {
+            for channel in channels {
+                let status = channel
+                    .get("id")
+                    .and_then(OwnedValue::as_str)
+                    .and_then(|id| id.parse().ok())
+                    .and_then(Id::new_checked)
+                    .and_then(|id| self.voice_channel_statuses.get(&id));
+
+                if let (Some(status), Some(channel)) = (status, channel.as_object_mut()) {
+                    channel.insert(String::from("status"), status.clone().into());
+                }
+            }
+        }
+
+        guild.serialize(serializer)
+    }
+}
+
+/// Scheduled events by guild, which twilight's cache does not keep track of.
+type ScheduledEvents =
+    RwLock<HashMap<Id<GuildMarker>, HashMap<Id<ScheduledEventMarker>, GuildScheduledEvent>>>;
+
+/// Auto moderation rules by guild, which twilight's cache does not keep track
+/// of.
+type AutoModerationRules =
+    RwLock<HashMap<Id<GuildMarker>, HashMap<Id<AutoModerationRuleMarker>, AutoModerationRule>>>;
+
+/// Statuses of voice channels by guild, which twilight's cache does not keep
+/// track of.
+type VoiceChannelStatuses = RwLock<HashMap<Id<GuildMarker>, HashMap<Id<ChannelMarker>, String>>>;
+
+/// Member counts of threads by guild from THREAD_MEMBERS_UPDATEs, which
+/// twilight's cache does not apply to the threads.
+type ThreadMemberCounts = RwLock<HashMap<Id<GuildMarker>, HashMap<Id<ChannelMarker>, u8>>>;
+
+/// Guilds in order of their most recent event, for evicting the least recently
+/// active ones when the cache is full.
+#[derive(Default)]
+struct GuildLru {
+    tick: u64,
+    last_active: HashMap<Id<GuildMarker>, u64>,
+    order: BTreeMap<u64, Id<GuildMarker>>,
+}
+
+impl GuildLru {
+    fn touch(&mut self, guild_id: Id<GuildMarker>) {
+        self.tick += 1;
+
+        if let Some(previous) = self.last_active.insert(guild_id, self.tick) {
+            self.order.remove(&previous);
+        }
+
+        self.order.insert(self.tick, guild_id);
+    }
+
+    fn remove(&mut self, guild_id: Id<GuildMarker>) {
+        if let Some(previous) = self.last_active.remove(&guild_id) {
+            self.order.remove(&previous);
+        }
+    }
+
+    fn pop_least_recent(&mut self) -> Option<Id<GuildMarker>> {
+        let (_, guild_id) = self.order.pop_first()?;
+        self.last_active.remove(&guild_id);
+
+        Some(guild_id)
+    }
+}
+
+/// Changes to the cache caused by a single update.
+#[derive(Debug, PartialEq, Eq)]
+pub struct CacheUpdateStats {
+    pub guilds_added: u8,
+    pub guilds_removed: u8,
+    pub members_added: u32,
+    pub channels_added: u32,
+}
+
+/// Sizes of the cache of a shard.
+#[derive(Serialize)]
+pub struct CacheStats {
+    pub shard: u32,
+    pub channels: usize,
+    pub emojis: usize,
+    pub guilds: usize,
+    pub members: usize,
+    pub presences: usize,
+    pub roles: usize,
+    pub scheduled_events: usize,
+    pub threads: usize,
+    pub unavailable_guilds: usize,
+    pub users: usize,
+    pub voice_states: usize,
+}
+
+pub struct Guilds {
+    cache: Arc<InMemoryCache>,
+    resource_types: ResourceType,
+    shard_id: u32,
+    shard_count: u32,
+    scheduled_events: ScheduledEvents,
+    auto_moderation_rules: AutoModerationRules,
+    thread_member_counts: ThreadMemberCounts,
+    voice_channel_statuses: VoiceChannelStatuses,
+    lru: Mutex<GuildLru>,
+}
 
 impl Guilds {
-    pub fn new(cache: Arc<InMemoryCache>, shard_id: u32) -> Self {
-        Self(cache, shard_id)
+    pub fn new(
+        mut resource_types: ResourceType,
+        intents: Intents,
+        shard_id: u32,
+        shard_count: u32,
+    ) -> Self {
+        // Discord only sends presences with the GUILD_PRESENCES intent, so there
+        // is no point in keeping track of them without it
+        if !intents.contains(Intents::GUILD_PRESENCES) {
+            resource_types.remove(ResourceType::PRESENCE);
+        }
+
+        let cache = InMemoryCache::builder()
+            .resource_types(resource_types)
+            .message_cache_size(0)
+            .build();
+
+        Self {
+            cache: Arc::new(cache),
+            resource_types,
+            shard_id,
+            shard_count,
+            scheduled_events: RwLock::new(HashMap::new()),
+            auto_moderation_rules: RwLock::new(HashMap::new()),
+            thread_member_counts: RwLock::new(HashMap::new()),
+            voice_channel_statuses: RwLock::new(HashMap::new()),
+            lru: Mutex::new(GuildLru::default()),
+        }
     }
 
     pub fn update(&self, value: impl UpdateCache) {
-        self.0.update(value);
+        self.cache.update(value);
+    }
+
+    /// Update the cache and count what the update added to or removed from it.
+    ///
+    /// The changes are derived from the event and lookups of the items it
+    /// contains, since comparing [`InMemoryCache::stats`] before and after the
+    /// update would count every cached item twice per event.
+    pub fn update_with_stats(&self, event: GatewayEvent) -> CacheUpdateStats {
+        let stats = self.update_stats(&event);
+        self.update(event);
+
+        stats
+    }
+
+    fn update_stats(&self, event: &GatewayEvent) -> CacheUpdateStats {
+        let mut stats = CacheUpdateStats {
+            guilds_added: 0,
+            guilds_removed: 0,
+            members_added: 0,
+            channels_added: 0,
+        };
+
+        match event {
+            GatewayEvent::GuildCreate(guild) => {
+                stats.guilds_added = u8::from(self.cache.guild(guild.id).is_none());
+                stats.members_added = self
+                    .count_new_members(guild.id, guild.members.iter().map(|member| member.user.id));
+                stats.channels_added = self.count_new_channels(
+                    guild
+                        .channels
+                        .iter()
+                        .chain(&guild.threads)
+                        .map(|channel| channel.id),
+                );
+            }
+            GatewayEvent::GuildDelete(delete) if !delete.unavailable => {
+                stats.guilds_removed = u8::from(self.cache.guild(delete.id).is_some());
+            }
+            GatewayEvent::MemberAdd(add) => {
+                stats.members_added =
+                    self.count_new_members(add.guild_id, iter::once(add.member.user.id));
+            }
+            GatewayEvent::MemberChunk(chunk) => {
+                stats.members_added = self.count_new_members(
+                    chunk.guild_id,
+                    chunk.members.iter().map(|member| member.user.id),
+                );
+            }
+            GatewayEvent::ChannelCreate(create) => {
+                stats.channels_added = self.count_new_channels(iter::once(create.id));
+            }
+            GatewayEvent::ThreadCreate(create) => {
+                stats.channels_added = self.count_new_channels(iter::once(create.id));
+            }
+            GatewayEvent::ThreadListSync(sync) => {
+                stats.channels_added =
+                    self.count_new_channels(sync.threads.iter().map(|thread| thread.id));
+            }
+            _ => {}
+        }
+
+        stats
+    }
+
+    /// Amount of these members of a guild that are not cached yet.
+    fn count_new_members(
+        &self,
+        guild_id: Id<GuildMarker>,
+        user_ids: impl Iterator<Item = Id<UserMarker>>,
+    ) -> u32 {
+        if !self.resource_types.contains(ResourceType::MEMBER) {
+            return 0;
+        }
+
+        let count = user_ids
+            .filter(|user_id| self.cache.member(guild_id, *user_id).is_none())
+            .count();
+
+        u32::try_from(count).unwrap_or(u32::MAX)
+    }
+
+    /// Amount of these channels and threads that are not cached yet.
+    fn count_new_channels(&self, channel_ids: impl Iterator<Item = Id<ChannelMarker>>) -> u32 {
+        if !self.resource_types.contains(ResourceType::CHANNEL) {
+            return 0;
+        }
+
+        let count = channel_ids
+            .filter(|channel_id| self.cache.channel(*channel_id).is_none())
+            .count();
+
+        u32::try_from(count).unwrap_or(u32::MAX)
+    }
+
+    /// Replace the scheduled events of a guild with the ones from its raw
+    /// GUILD_CREATE.
+    pub fn set_guild_extras(&self, extras: GuildExtras) {
+        let events = extras
+            .guild_scheduled_events
+            .into_iter()
+            .map(|event| (event.id, event))
+            .collect();
+
+        self.scheduled_events
+            .write()
+            .unwrap()
+            .insert(extras.id, events);
+    }
+
+    /// Update the scheduled events from the events that affect them.
+    pub fn update_scheduled_events(&self, event: &GatewayEvent) {
+        match event {
+            GatewayEvent::GuildScheduledEventCreate(create) => {
+                self.insert_scheduled_event(&create.0);
+            }
+            GatewayEvent::GuildScheduledEventUpdate(update) => {
+                self.insert_scheduled_event(&update.0);
+            }
+            GatewayEvent::GuildScheduledEventDelete(delete) => {
+                let mut scheduled_events = self.scheduled_events.write().unwrap();
+
+                if let Some(events) = scheduled_events.get_mut(&delete.0.guild_id) {
+                    events.remove(&delete.0.id);
+                }
+            }
+            GatewayEvent::GuildDelete(delete) if !delete.unavailable => {
+                self.scheduled_events.write().unwrap().remove(&delete.id);
+            }
+            _ => {}
+        }
+    }
+
+    fn insert_scheduled_event(&self, event: &GuildScheduledEvent) {
+        self.scheduled_events
+            .write()
+            .unwrap()
+            .entry(event.guild_id)
+            .or_default()
+            .insert(event.id, event.clone());
+    }
+
+    /// Update the auto moderation rules from the events that affect them.
+    pub fn update_auto_moderation_rules(&self, event: &GatewayEvent) {
+        match event {
+            GatewayEvent::AutoModerationRuleCreate(create) => {
+                self.insert_auto_moderation_rule(&create.0);
+            }
+            GatewayEvent::AutoModerationRuleUpdate(update) => {
+                self.insert_auto_moderation_rule(&update.0);
+            }
+            GatewayEvent::AutoModerationRuleDelete(delete) => {
+                let mut auto_moderation_rules = self.auto_moderation_rules.write().unwrap();
+
+                if let Some(rules) = auto_moderation_rules.get_mut(&delete.0.guild_id) {
+                    rules.remove(&delete.0.id);
+                }
+            }
+            GatewayEvent::GuildDelete(delete) if !delete.unavailable => {
+                self.auto_moderation_rules
+                    .write()
+                    .unwrap()
+                    .remove(&delete.id);
+            }
+            _ => {}
+        }
+    }
+
+    fn insert_auto_moderation_rule(&self, rule: &AutoModerationRule) {
+        self.auto_moderation_rules
+            .write()
+            .unwrap()
+            .entry(rule.guild_id)
+            .or_default()
+            .insert(rule.id, rule.clone());
+    }
+
+    /// Update the member counts of threads from the events that affect them.
+    pub fn update_thread_member_counts(&self, event: &GatewayEvent) {
+        match event {
+            GatewayEvent::ThreadMembersUpdate(update) => {
+                self.thread_member_counts
+                    .write()
+                    .unwrap()
+                    .entry(update.guild_id)
+                    .or_default()
+                    .insert(
+                        update.id,
+                        u8::try_from(update.member_count).unwrap_or(u8::MAX),
+                    );
+            }
+            // These contain the thread with an up to date member count
+            GatewayEvent::ThreadUpdate(update) => {
+                if let Some(guild_id) = update.guild_id {
+                    self.remove_thread_member_count(guild_id, update.id);
+                }
+            }
+            GatewayEvent::ThreadDelete(delete) => {
+                self.remove_thread_member_count(delete.guild_id, delete.id);
+            }
+            GatewayEvent::ThreadListSync(sync) => {
+                for thread in &sync.threads {
+                    self.remove_thread_member_count(sync.guild_id, thread.id);
+                }
+            }
+            GatewayEvent::GuildDelete(delete) if !delete.unavailable => {
+                self.thread_member_counts
+                    .write()
+                    .unwrap()
+                    .remove(&delete.id);
+            }
+            _ => {}
+        }
+    }
+
+    /// Update the status of a voice channel from its raw
+    /// VOICE_CHANNEL_STATUS_UPDATE.
+    pub fn set_voice_channel_status(&self, status: VoiceChannelStatus) {
+        let mut voice_channel_statuses = self.voice_channel_statuses.write().unwrap();
+        let statuses = voice_channel_statuses.entry(status.guild_id).or_default();
+
+        match status.status {
+            Some(text) if !text.is_empty() => {
+                statuses.insert(status.id, text);
+            }
+            _ => {
+                statuses.remove(&status.id);
+            }
+        }
+    }
+
+    /// Remove the statuses of voice channels that were deleted.
+    pub fn update_voice_channel_statuses(&self, event: &GatewayEvent) {
+        match event {
+            GatewayEvent::ChannelDelete(delete) => {
+                if let Some(guild_id) = delete.guild_id {
+                    let mut voice_channel_statuses = self.voice_channel_statuses.write().unwrap();
+
+                    if let Some(statuses) = voice_channel_statuses.get_mut(&guild_id) {
+                        statuses.remove(&delete.id);
+                    }
+                }
+            }
+            GatewayEvent::GuildDelete(delete) if !delete.unavailable => {
+                self.voice_channel_statuses
+                    .write()
+                    .unwrap()
+                    .remove(&delete.id);
+            }
+            _ => {}
+        }
+    }
+
+    fn voice_channel_statuses_in_guild(
+        &self,
+        guild_id: Id<GuildMarker>,
+    ) -> HashMap<Id<ChannelMarker>, String> {
+        self.voice_channel_statuses
+            .read()
+            .unwrap()
+            .get(&guild_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn remove_thread_member_count(&self, guild_id: Id<GuildMarker>, thread_id: Id<ChannelMarker>) {
+        let mut thread_member_counts = self.thread_member_counts.write().unwrap();
+
+        if let Some(counts) = thread_member_counts.get_mut(&guild_id) {
+            counts.remove(&thread_id);
+        }
+    }
+
+    /// Mark a guild as recently active, so that it is evicted last.
+    pub fn touch(&self, guild_id: Id<GuildMarker>) {
+        self.lru.lock().unwrap().touch(guild_id);
+    }
+
+    /// Stop tracking the activity of a guild that was removed.
+    pub fn forget(&self, guild_id: Id<GuildMarker>) {
+        self.lru.lock().unwrap().remove(guild_id);
+    }
+
+    /// Evict the least recently active guilds until the cache is within the
+    /// configured limits.
+    ///
+    /// Returns the resource whose limit was exceeded for every evicted guild.
+    pub fn enforce_limits(&self) -> Vec<&'static str> {
+        let limits = &CONFIG.cache.limits;
+        let mut evicted = Vec::new();
+
+        loop {
+            let stats = self.stats();
+
+            let exceeded = [
+                ("guilds", stats.guilds(), limits.guilds),
+                ("members", stats.members(), limits.members),
+                ("channels", stats.channels(), limits.channels),
+                ("roles", stats.roles(), limits.roles),
+            ]
+            .into_iter()
+            .find_map(|(resource, count, limit)| {
+                limit.filter(|limit| count > *limit).map(|_| resource)
+            });
+
+            let Some(resource) = exceeded else {
+                break;
+            };
+
+            let Some(guild_id) = self.lru.lock().unwrap().pop_least_recent() else {
+                break;
+            };
+
+            self.evict(guild_id);
+            evicted.push(resource);
+        }
+
+        evicted
+    }
+
+    /// Remove a guild and everything cached for it, as if the bot left it.
+    fn evict(&self, guild_id: Id<GuildMarker>) {
+        let event = GatewayEvent::GuildDelete(GuildDelete {
+            id: guild_id,
+            unavailable: false,
+        });
+
+        self.update_scheduled_events(&event);
+        self.update_auto_moderation_rules(&event);
+        self.update_thread_member_counts(&event);
+        self.update_voice_channel_statuses(&event);
+        self.update(event);
     }
 
     pub fn stats(&self) -> InMemoryCacheStats {
-        self.0.stats()
+        self.cache.stats()
     }
 
-    pub fn get_ready_payload(&self, mut ready: JsonObject, sequence: &mut usize) -> Payload {
+    /// Amount of cached threads, which twilight counts as channels.
+    pub fn thread_count(&self) -> usize {
+        self.cache
+            .iter()
+            .channels()
+            .filter(|channel| channel.kind.is_thread())
+            .count()
+    }
+
+    pub fn scheduled_event_count(&self) -> usize {
+        self.scheduled_events
+            .read()
+            .unwrap()
+            .values()
+            .map(HashMap::len)
+            .sum()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        let stats = self.stats();
+
+        CacheStats {
+            shard: self.shard_id,
+            channels: stats.channels(),
+            emojis: stats.emojis(),
+            guilds: stats.guilds(),
+            members: stats.members(),
+            presences: stats.presences(),
+            roles: stats.roles(),
+            scheduled_events: self.scheduled_event_count(),
+            threads: self.thread_count(),
+            unavailable_guilds: stats.unavailable_guilds(),
+            users: stats.users(),
+            voice_states: stats.voice_states(),
+        }
+    }
+
+    /// Create a READY for a client from the one received by the shard.
+    ///
+    /// The session ID is the one of the client's session with the proxy and the
+    /// resume gateway URL points to the proxy, since that is what the client
+    /// will have to RESUME with.
+    pub fn get_ready_payload(
+        &self,
+        mut ready: JsonObject,
+        session_id: String,
+        resume_gateway_url: &str,
+        sequence: &mut usize,
+    ) -> Payload {
         *sequence += 1;
 
+        ready.insert(String::from("session_id"), OwnedValue::String(session_id));
+
+        // Clients have to resume with the proxy, not with Discord
+        ready.insert(
+            String::from("resume_gateway_url"),
+            resume_gateway_url.into(),
+        );
+
         let unavailable_guilds = self
-            .0
+            .cache
             .iter()
             .guilds()
             .map(|guild| {
@@ -87,6 +656,11 @@ impl Guilds {
             OwnedValue::Array(unavailable_guilds),
         );
 
+        ready.insert(
+            String::from("shard"),
+            OwnedValue::Array(vec![self.shard_id.into(), self.shard_count.into()]),
+        );
+
         Payload {
             d: Event::Ready(ready),
             op: OpCode::Dispatch,
@@ -96,13 +670,13 @@ impl Guilds {
     }
 
     fn channels_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Channel> {
-        self.0
+        self.cache
             .guild_channels(guild_id)
             .map(|reference| {
                 reference
                     .iter()
                     .filter_map(|channel_id| {
-                        let channel = self.0.channel(*channel_id)?;
+                        let channel = self.cache.channel(*channel_id)?;
 
                         if channel.kind.is_thread() {
                             None
@@ -116,13 +690,13 @@ impl Guilds {
     }
 
     fn presences_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Presence> {
-        self.0
+        self.cache
             .guild_presences(guild_id)
             .map(|reference| {
                 reference
                     .iter()
                     .filter_map(|user_id| {
-                        let presence = self.0.presence(guild_id, *user_id)?;
+                        let presence = self.cache.presence(guild_id, *user_id)?;
 
                         Some(Presence {
                             activities: presence.activities().to_vec(),
@@ -140,13 +714,13 @@ impl Guilds {
     }
 
     fn emojis_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Emoji> {
-        self.0
+        self.cache
             .guild_emojis(guild_id)
             .map(|reference| {
                 reference
                     .iter()
                     .filter_map(|emoji_id| {
-                        let emoji = self.0.emoji(*emoji_id)?;
+                        let emoji = self.cache.emoji(*emoji_id)?;
 
                         Some(Emoji {
                             animated: emoji.animated(),
@@ -156,9 +730,9 @@ impl Guilds {
                             name: emoji.name().to_string(),
                             require_colons: emoji.require_colons(),
                             roles: emoji.roles().to_vec(),
-                            user: emoji
-                                .user_id()
-                                .and_then(|id| self.0.user(id).map(|user| user.value().clone())),
+                            user: emoji.user_id().and_then(|id| {
+                                self.cache.user(id).map(|user| user.value().clone())
+                            }),
                         })
                     })
                     .collect()
@@ -167,7 +741,7 @@ impl Guilds {
     }
 
     fn member(&self, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Option<Member> {
-        let member = self.0.member(guild_id, user_id)?;
+        let member = self.cache.member(guild_id, user_id)?;
 
         Some(Member {
             avatar: member.avatar(),
@@ -180,12 +754,12 @@ impl Guilds {
             pending: member.pending(),
             premium_since: member.premium_since(),
             roles: member.roles().to_vec(),
-            user: self.0.user(member.user_id())?.value().clone(),
+            user: self.cache.user(member.user_id())?.value().clone(),
         })
     }
 
     fn members_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Member> {
-        self.0
+        self.cache
             .guild_members(guild_id)
             .map(|reference| {
                 reference
@@ -197,25 +771,33 @@ impl Guilds {
     }
 
     fn roles_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Role> {
-        self.0
+        self.cache
             .guild_roles(guild_id)
             .map(|reference| {
                 reference
                     .iter()
-                    .filter_map(|role_id| Some(self.0.role(*role_id)?.value().resource().clone()))
+                    .filter_map(|role_id| {
+                        Some(self.cache.role(*role_id)?.value().resource().clone())
+                    })
                     .collect()
             })
             .unwrap_or_default()
     }
 
     fn stage_instances_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<StageInstance> {
-        self.0
+        self.cache
             .guild_stage_instances(guild_id)
             .map(|reference| {
                 reference
                     .iter()
                     .filter_map(|stage_id| {
-                        Some(self.0.stage_instance(*stage_id)?.value().resource().clone())
+                        Some(
+                            self.cache
+                                .stage_instance(*stage_id)?
+                                .value()
+                                .resource()
+                                .clone(),
+                        )
                     })
                     .collect()
             })
@@ -223,13 +805,13 @@ impl Guilds {
     }
 
     fn stickers_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Sticker> {
-        self.0
+        self.cache
             .guild_stickers(guild_id)
             .map(|reference| {
                 reference
                     .iter()
                     .filter_map(|sticker_id| {
-                        let sticker = self.0.sticker(*sticker_id)?;
+                        let sticker = self.cache.sticker(*sticker_id)?;
 
                         Some(Sticker {
                             available: sticker.available(),
@@ -242,9 +824,9 @@ impl Guilds {
                             pack_id: sticker.pack_id(),
                             sort_value: sticker.sort_value(),
                             tags: sticker.tags().to_string(),
-                            user: sticker
-                                .user_id()
-                                .and_then(|id| self.0.user(id).map(|user| user.value().clone())),
+                            user: sticker.user_id().and_then(|id| {
+                                self.cache.user(id).map(|user| user.value().clone())
+                            }),
                         })
                     })
                     .collect()
@@ -252,14 +834,18 @@ impl Guilds {
             .unwrap_or_default()
     }
 
+    /// Voice states with their guild member, if it is cached.
+    ///
+    /// Like Discord's, the member has no presence, which is sent separately in
+    /// the `presences` of the GUILD_CREATE if presences are cached.
     fn voice_states_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<VoiceState> {
-        self.0
+        self.cache
             .guild_voice_states(guild_id)
             .map(|reference| {
                 reference
                     .iter()
                     .filter_map(|user_id| {
-                        let voice_state = self.0.voice_state(*user_id, guild_id)?;
+                        let voice_state = self.cache.voice_state(*user_id, guild_id)?;
 
                         Some(VoiceState {
                             channel_id: Some(voice_state.channel_id()),
@@ -283,16 +869,27 @@ impl Guilds {
     }
 
     fn threads_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<Channel> {
-        self.0
+        let thread_member_counts = self.thread_member_counts.read().unwrap();
+        let member_counts = thread_member_counts.get(&guild_id);
+
+        self.cache
             .guild_channels(guild_id)
             .map(|reference| {
                 reference
                     .iter()
                     .filter_map(|channel_id| {
-                        let channel = self.0.channel(*channel_id)?;
+                        let channel = self.cache.channel(*channel_id)?;
 
                         if channel.kind.is_thread() {
-                            Some(channel.value().clone())
+                            let mut thread = channel.value().clone();
+
+                            if let Some(member_count) =
+                                member_counts.and_then(|counts| counts.get(&thread.id))
+                            {
+                                thread.member_count = Some(*member_count);
+                            }
+
+                            Some(thread)
                         } else {
                             None
                         }
@@ -302,96 +899,913 @@ impl Guilds {
             .unwrap_or_default()
     }
 
+    fn scheduled_events_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<GuildScheduledEvent> {
+        self.scheduled_events
+            .read()
+            .unwrap()
+            .get(&guild_id)
+            .map(|events| events.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn auto_moderation_rules_in_guild(&self, guild_id: Id<GuildMarker>) -> Vec<AutoModerationRule> {
+        self.auto_moderation_rules
+            .read()
+            .unwrap()
+            .get(&guild_id)
+            .map(|rules| rules.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
     pub fn get_guild_payloads<'a>(
         &'a self,
         sequence: &'a mut usize,
     ) -> impl Iterator<Item = Payload> + 'a {
-        self.0.iter().guilds().map(move |guild| {
-            *sequence += 1;
-
-            if guild.unavailable() {
-                let guild_delete = GuildDelete {
-                    id: guild.id(),
-                    unavailable: true,
-                };
-
-                Payload {
-                    d: Event::GuildDelete(guild_delete),
-                    op: OpCode::Dispatch,
-                    t: String::from("GUILD_DELETE"),
-                    s: *sequence,
-                }
-            } else {
-                let guild_channels = self.channels_in_guild(guild.id());
-                let presences = self.presences_in_guild(guild.id());
-                let emojis = self.emojis_in_guild(guild.id());
-                let members = self.members_in_guild(guild.id());
-                let roles = self.roles_in_guild(guild.id());
-                let stage_instances = self.stage_instances_in_guild(guild.id());
-                let stickers = self.stickers_in_guild(guild.id());
-                let voice_states = self.voice_states_in_guild(guild.id());
-                let threads = self.threads_in_guild(guild.id());
-
-                let new_guild = Guild {
-                    afk_channel_id: guild.afk_channel_id(),
-                    afk_timeout: guild.afk_timeout(),
-                    application_id: guild.application_id(),
-                    approximate_member_count: None, // Only present in with_counts HTTP endpoint
-                    banner: guild.banner().map(ToOwned::to_owned),
-                    approximate_presence_count: None, // Only present in with_counts HTTP endpoint
-                    channels: guild_channels,
-                    default_message_notifications: guild.default_message_notifications(),
-                    description: guild.description().map(ToString::to_string),
-                    discovery_splash: guild.discovery_splash().map(ToOwned::to_owned),
-                    emojis,
-                    explicit_content_filter: guild.explicit_content_filter(),
-                    features: guild.features().cloned().collect(),
-                    icon: guild.icon().map(ToOwned::to_owned),
-                    id: guild.id(),
-                    joined_at: guild.joined_at(),
-                    large: guild.large(),
-                    max_members: guild.max_members(),
-                    max_presences: guild.max_presences(),
-                    max_video_channel_users: None, // Not in the cache model
-                    member_count: guild.member_count(),
-                    members,
-                    mfa_level: guild.mfa_level(),
-                    name: guild.name().to_string(),
-                    nsfw_level: guild.nsfw_level(),
-                    owner_id: guild.owner_id(),
-                    owner: guild.owner(),
-                    permissions: guild.permissions(),
-                    public_updates_channel_id: guild.public_updates_channel_id(),
-                    preferred_locale: guild.preferred_locale().to_string(),
-                    premium_progress_bar_enabled: guild.premium_progress_bar_enabled(),
-                    premium_subscription_count: guild.premium_subscription_count(),
-                    premium_tier: guild.premium_tier(),
-                    presences,
-                    roles,
-                    rules_channel_id: guild.rules_channel_id(),
-                    splash: guild.splash().map(ToOwned::to_owned),
-                    stage_instances,
-                    stickers,
-                    system_channel_flags: guild.system_channel_flags(),
-                    system_channel_id: guild.system_channel_id(),
-                    threads,
-                    unavailable: false,
-                    vanity_url_code: guild.vanity_url_code().map(ToString::to_string),
-                    verification_level: guild.verification_level(),
-                    voice_states,
-                    widget_channel_id: guild.widget_channel_id(),
-                    widget_enabled: guild.widget_enabled(),
-                };
-
-                let guild_create = GuildCreate(new_guild);
-
-                Payload {
-                    d: Event::GuildCreate(Box::new(guild_create)),
-                    op: OpCode::Dispatch,
-                    t: String::from("GUILD_CREATE"),
-                    s: *sequence,
-                }
+        self.cache
+            .iter()
+            .guilds()
+            .map(move |guild| self.guild_payload(&guild, sequence))
+    }
+
+    /// Payload for a single guild, as if the client just identified, or
+    /// nothing if the guild is not cached.
+    pub fn get_guild_payload(
+        &self,
+        guild_id: Id<GuildMarker>,
+        sequence: &mut usize,
+    ) -> Option<Payload> {
+        self.cache
+            .guild(guild_id)
+            .map(|guild| self.guild_payload(&guild, sequence))
+    }
+
+    /// The GUILD_CREATE or GUILD_DELETE of a guild.
+    fn guild_payload(&self, guild: &CachedGuild, sequence: &mut usize) -> Payload {
+        *sequence += 1;
+
+        if guild.unavailable() {
+            let guild_delete = GuildDelete {
+                id: guild.id(),
+                unavailable: true,
+            };
+
+            Payload {
+                d: Event::GuildDelete(guild_delete),
+                op: OpCode::Dispatch,
+                t: String::from("GUILD_DELETE"),
+                s: *sequence,
             }
+        } else {
+            let guild_channels = self.channels_in_guild(guild.id());
+            let presences = self.presences_in_guild(guild.id());
+            let emojis = self.emojis_in_guild(guild.id());
+            let members = self.members_in_guild(guild.id());
+            let roles = self.roles_in_guild(guild.id());
+            let stage_instances = self.stage_instances_in_guild(guild.id());
+            let stickers = self.stickers_in_guild(guild.id());
+            let voice_states = self.voice_states_in_guild(guild.id());
+            let threads = self.threads_in_guild(guild.id());
+            let guild_scheduled_events = self.scheduled_events_in_guild(guild.id());
+            let auto_moderation_rules = self.auto_moderation_rules_in_guild(guild.id());
+            let voice_channel_statuses = self.voice_channel_statuses_in_guild(guild.id());
+
+            let new_guild = Guild {
+                afk_channel_id: guild.afk_channel_id(),
+                afk_timeout: guild.afk_timeout(),
+                application_id: guild.application_id(),
+                approximate_member_count: None, // Only present in with_counts HTTP endpoint
+                banner: guild.banner().map(ToOwned::to_owned),
+                approximate_presence_count: None, // Only present in with_counts HTTP endpoint
+                channels: guild_channels,
+                default_message_notifications: guild.default_message_notifications(),
+                description: guild.description().map(ToString::to_string),
+                discovery_splash: guild.discovery_splash().map(ToOwned::to_owned),
+                emojis,
+                explicit_content_filter: guild.explicit_content_filter(),
+                features: guild.features().cloned().collect(),
+                icon: guild.icon().map(ToOwned::to_owned),
+                id: guild.id(),
+                joined_at: guild.joined_at(),
+                large: guild.large(),
+                max_members: guild.max_members(),
+                max_presences: guild.max_presences(),
+                max_video_channel_users: None, // Not in the cache model
+                member_count: guild.member_count(),
+                members,
+                mfa_level: guild.mfa_level(),
+                name: guild.name().to_string(),
+                nsfw_level: guild.nsfw_level(),
+                owner_id: guild.owner_id(),
+                owner: guild.owner(),
+                permissions: guild.permissions(),
+                public_updates_channel_id: guild.public_updates_channel_id(),
+                preferred_locale: guild.preferred_locale().to_string(),
+                premium_progress_bar_enabled: guild.premium_progress_bar_enabled(),
+                premium_subscription_count: guild.premium_subscription_count(),
+                premium_tier: guild.premium_tier(),
+                presences,
+                roles,
+                rules_channel_id: guild.rules_channel_id(),
+                splash: guild.splash().map(ToOwned::to_owned),
+                stage_instances,
+                stickers,
+                system_channel_flags: guild.system_channel_flags(),
+                system_channel_id: guild.system_channel_id(),
+                threads,
+                unavailable: false,
+                vanity_url_code: guild.vanity_url_code().map(ToString::to_string),
+                verification_level: guild.verification_level(),
+                voice_states,
+                widget_channel_id: guild.widget_channel_id(),
+                widget_enabled: guild.widget_enabled(),
+            };
+
+            let guild_create = GuildCreate {
+                guild: new_guild,
+                guild_scheduled_events,
+                auto_moderation_rules,
+                voice_channel_statuses,
+            };
+
+            Payload {
+                d: Event::GuildCreate(Box::new(guild_create)),
+                op: OpCode::Dispatch,
+                t: String::from("GUILD_CREATE"),
+                s: *sequence,
+            }
+        }
+    }
+}
+
+/// The READY and GUILD_CREATEs sent to clients are built from different types
+/// with simd-json and serde_json, so these check that both builds send the
+/// same JSON. Run them with both `cargo test` and
+/// `cargo test --no-default-features --features no-simd`.
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+    #[cfg(not(feature = "simd-json"))]
+    use serde_json::to_string;
+    use serde_json::{json, Value};
+    #[cfg(feature = "simd-json")]
+    use simd_json::to_string;
+    use twilight_cache_inmemory::ResourceType;
+    use twilight_gateway::{parse, EventTypeFlags};
+    use twilight_model::{
+        gateway::{
+            event::{Event, GatewayEvent as TwilightGatewayEvent},
+            payload::incoming::{GuildCreate, GuildDelete},
+            Intents,
+        },
+        id::Id,
+    };
+
+    use super::{CacheUpdateStats, Guilds, Payload};
+    use crate::model::{JsonObject, Ready, VoiceChannelStatus};
+
+    const GUILD: &str = r#"{
+        "id": "1000",
+        "name": "Parity",
+        "icon": null,
+        "splash": null,
+        "discovery_splash": null,
+        "owner_id": "2000",
+        "afk_channel_id": null,
+        "afk_timeout": 300,
+        "widget_enabled": false,
+        "widget_channel_id": null,
+        "verification_level": 1,
+        "default_message_notifications": 0,
+        "explicit_content_filter": 0,
+        "roles": [{
+            "id": "1000",
+            "name": "@everyone",
+            "color": 0,
+            "hoist": false,
+            "icon": null,
+            "unicode_emoji": null,
+            "position": 0,
+            "permissions": "1071698660929",
+            "managed": false,
+            "mentionable": false,
+            "flags": 0
+        }],
+        "emojis": [],
+        "features": ["COMMUNITY"],
+        "mfa_level": 0,
+        "application_id": null,
+        "system_channel_id": "3000",
+        "system_channel_flags": 0,
+        "rules_channel_id": null,
+        "max_members": 500000,
+        "vanity_url_code": null,
+        "description": null,
+        "banner": null,
+        "premium_tier": 0,
+        "premium_subscription_count": 0,
+        "preferred_locale": "en-US",
+        "public_updates_channel_id": null,
+        "nsfw_level": 0,
+        "premium_progress_bar_enabled": false,
+        "stickers": [],
+        "joined_at": "2023-01-01T00:00:00.000000+00:00",
+        "large": false,
+        "unavailable": false,
+        "member_count": 1,
+        "voice_states": [],
+        "members": [],
+        "channels": [{
+            "id": "3000",
+            "type": 0,
+            "name": "general",
+            "position": 0,
+            "permission_overwrites": [],
+            "nsfw": false,
+            "topic": null,
+            "last_message_id": null,
+            "rate_limit_per_user": 0,
+            "parent_id": null
+        }],
+        "threads": [],
+        "presences": [],
+        "stage_instances": [],
+        "guild_scheduled_events": []
+    }"#;
+
+    /// URL of the proxy clients resume with.
+    const RESUME_GATEWAY_URL: &str = "ws://localhost:7878";
+
+    /// What clients get for [`READY`] and [`GUILD`], which both JSON libraries
+    /// have to serialize the same.
+    const READY_PAYLOAD: &str = include_str!("../tests/fixtures/ready.json");
+    const GUILD_CREATE_PAYLOAD: &str = include_str!("../tests/fixtures/guild_create.json");
+
+    const READY: &str = r#"{
+        "v": 10,
+        "user": {"id": "2000", "username": "proxy", "discriminator": "0", "bot": true},
+        "guilds": [{"id": "1000", "unavailable": true}],
+        "session_id": "discord",
+        "resume_gateway_url": "wss://gateway.discord.gg",
+        "shard": [0, 1],
+        "application": {"id": "2000", "flags": 0}
+    }"#;
+
+    #[cfg(feature = "simd-json")]
+    fn from_json<T: DeserializeOwned>(json: &str) -> T {
+        unsafe { simd_json::from_str(&mut json.to_string()) }.unwrap()
+    }
+
+    #[cfg(not(feature = "simd-json"))]
+    fn from_json<T: DeserializeOwned>(json: &str) -> T {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// Decode what would be sent to a client with the JSON library of this
+    /// build.
+    fn sent(payload: &Payload) -> Value {
+        serde_json::from_str(&to_string(payload).unwrap()).unwrap()
+    }
+
+    /// A cache with one guild.
+    fn guilds() -> Guilds {
+        let guilds = Guilds::new(ResourceType::all(), Intents::all(), 0, 1);
+        guilds.update(Event::GuildCreate(Box::new(GuildCreate(from_json(GUILD)))));
+
+        guilds
+    }
+
+    /// The `d` of a READY from Discord, as dispatch stores it.
+    fn upstream_ready(d: &str) -> JsonObject {
+        from_json::<Ready>(&format!(r#"{{"op":0,"s":1,"t":"READY","d":{d}}}"#)).d
+    }
+
+    /// An event as dispatch parses it from what Discord sent.
+    fn event(event_type: &str, d: &Value) -> Event {
+        let payload = json!({"op": 0, "s": 1, "t": event_type, "d": d});
+
+        match parse(payload.to_string(), EventTypeFlags::all()) {
+            Ok(Some(TwilightGatewayEvent::Dispatch(_, event))) => Event::from(event),
+            _ => panic!("{event_type} could not be parsed"),
+        }
+    }
+
+    /// [`GUILD`] with some of its fields replaced.
+    fn guild_with(fields: &Value) -> Value {
+        let mut guild: Value = serde_json::from_str(GUILD).unwrap();
+
+        for (key, value) in fields.as_object().unwrap() {
+            guild[key] = value.clone();
+        }
+
+        guild
+    }
+
+    /// The `d` of the GUILD_CREATE a client would get for the only cached
+    /// guild.
+    fn guild_create(guilds: &Guilds) -> Value {
+        let payloads: Vec<Payload> = guilds.get_guild_payloads(&mut 0).collect();
+
+        sent(&payloads[0])["d"].take()
+    }
+
+    /// A cache that only saw the GUILD_CREATE of `guild`.
+    fn cached(guild: &Value) -> Guilds {
+        let guilds = Guilds::new(ResourceType::all(), Intents::all(), 0, 1);
+        guilds.update(event("GUILD_CREATE", guild));
+
+        guilds
+    }
+
+    /// A public thread in `parent_id`.
+    fn thread(id: &str, parent_id: &str) -> Value {
+        json!({
+            "id": id,
+            "type": 11,
+            "guild_id": "1000",
+            "parent_id": parent_id,
+            "owner_id": "2000",
+            "name": "thread",
+            "last_message_id": null,
+            "message_count": 1,
+            "member_count": 1,
+            "rate_limit_per_user": 0,
+            "flags": 0,
+            "thread_metadata": {
+                "archived": false,
+                "auto_archive_duration": 1440,
+                "archive_timestamp": "2023-01-01T00:00:00.000000+00:00",
+                "locked": false
+            }
+        })
+    }
+
+    /// A guild member without a guild avatar, nickname or roles.
+    fn member(user_id: &str) -> Value {
+        json!({
+            "user": {
+                "id": user_id,
+                "username": "member",
+                "discriminator": "0",
+                "avatar": null
+            },
+            "nick": null,
+            "avatar": null,
+            "roles": [],
+            "joined_at": "2023-01-01T00:00:00.000000+00:00",
+            "premium_since": null,
+            "deaf": false,
+            "mute": false,
+            "flags": 0,
+            "pending": false
         })
     }
+
+    #[test]
+    fn ready_payload() {
+        let guilds = guilds();
+        let mut sequence = 0;
+
+        let ready = guilds.get_ready_payload(
+            from_json(READY),
+            String::from("proxy"),
+            RESUME_GATEWAY_URL,
+            &mut sequence,
+        );
+        let expected: Value = serde_json::from_str(READY_PAYLOAD).unwrap();
+
+        assert_eq!(sent(&ready), expected);
+        assert_eq!(serde_json::to_value(&ready).unwrap(), expected);
+    }
+
+    #[test]
+    fn guild_payloads() {
+        let guilds = guilds();
+        let mut sequence = 1;
+
+        let payloads: Vec<Payload> = guilds.get_guild_payloads(&mut sequence).collect();
+        assert_eq!(payloads.len(), 1);
+
+        let expected: Value = serde_json::from_str(GUILD_CREATE_PAYLOAD).unwrap();
+
+        assert_eq!(sent(&payloads[0]), expected);
+        assert_eq!(serde_json::to_value(&payloads[0]).unwrap(), expected);
+    }
+
+    #[test]
+    fn update_stats() {
+        let guilds = Guilds::new(ResourceType::all(), Intents::all(), 0, 1);
+        let guild_create = || Event::GuildCreate(Box::new(GuildCreate(from_json(GUILD))));
+
+        assert_eq!(
+            guilds.update_with_stats(guild_create()),
+            CacheUpdateStats {
+                guilds_added: 1,
+                guilds_removed: 0,
+                members_added: 0,
+                channels_added: 1,
+            }
+        );
+
+        // Everything is already cached the second time
+        assert_eq!(
+            guilds.update_with_stats(guild_create()),
+            CacheUpdateStats {
+                guilds_added: 0,
+                guilds_removed: 0,
+                members_added: 0,
+                channels_added: 0,
+            }
+        );
+
+        let guild_delete = Event::GuildDelete(GuildDelete {
+            id: Id::new(1000),
+            unavailable: false,
+        });
+
+        assert_eq!(
+            guilds.update_with_stats(guild_delete),
+            CacheUpdateStats {
+                guilds_added: 0,
+                guilds_removed: 1,
+                members_added: 0,
+                channels_added: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn ready_keeps_user() {
+        let ready = guilds().get_ready_payload(
+            upstream_ready(READY),
+            String::from("proxy"),
+            RESUME_GATEWAY_URL,
+            &mut 0,
+        );
+
+        assert_eq!(
+            sent(&ready)["d"]["user"],
+            json!({"id": "2000", "username": "proxy", "discriminator": "0", "bot": true})
+        );
+    }
+
+    #[test]
+    fn ready_keeps_informational_fields() {
+        let mut upstream: Value = serde_json::from_str(READY).unwrap();
+        upstream["guild_hashes"] = json!({
+            "1000": {
+                "version": 1,
+                "roles": {"hash": "Zm9v", "omitted": false},
+                "channels": {"hash": "YmFy", "omitted": false},
+                "metadata": {"hash": "YmF6", "omitted": false}
+            }
+        });
+        upstream["geo_ordered_rtc_regions"] = json!(["rotterdam", "frankfurt", "london"]);
+
+        let ready = guilds().get_ready_payload(
+            upstream_ready(&upstream.to_string()),
+            String::from("proxy"),
+            RESUME_GATEWAY_URL,
+            &mut 0,
+        );
+        let ready = sent(&ready);
+
+        assert_eq!(ready["d"]["guild_hashes"], upstream["guild_hashes"]);
+        assert_eq!(
+            ready["d"]["geo_ordered_rtc_regions"],
+            upstream["geo_ordered_rtc_regions"]
+        );
+    }
+
+    #[test]
+    fn guild_moderation_settings() {
+        let guild = guild_with(&json!({
+            "preferred_locale": "de",
+            "verification_level": 3,
+            "mfa_level": 1,
+            "explicit_content_filter": 2,
+            "default_message_notifications": 1
+        }));
+
+        let guild_create = guild_create(&cached(&guild));
+
+        for field in [
+            "preferred_locale",
+            "verification_level",
+            "mfa_level",
+            "explicit_content_filter",
+            "default_message_notifications",
+        ] {
+            assert_eq!(guild_create[field], guild[field], "{field}");
+        }
+    }
+
+    #[test]
+    fn guild_channel_ids() {
+        let guild = guild_with(&json!({
+            "system_channel_id": "3000",
+            "rules_channel_id": "3001",
+            "public_updates_channel_id": "3002"
+        }));
+
+        let guild_create = guild_create(&cached(&guild));
+
+        assert_eq!(guild_create["system_channel_id"], "3000");
+        assert_eq!(guild_create["rules_channel_id"], "3001");
+        assert_eq!(guild_create["public_updates_channel_id"], "3002");
+    }
+
+    #[test]
+    fn guild_membership_metadata() {
+        let guild = guild_with(&json!({
+            "joined_at": "2021-06-01T12:30:00.000000+00:00",
+            "large": true,
+            "premium_tier": 2,
+            "premium_subscription_count": 14
+        }));
+
+        let guild_create = guild_create(&cached(&guild));
+
+        for field in [
+            "joined_at",
+            "large",
+            "premium_tier",
+            "premium_subscription_count",
+        ] {
+            assert_eq!(guild_create[field], guild[field], "{field}");
+        }
+    }
+
+    #[test]
+    fn guild_nsfw_level_and_features() {
+        let guild = guild_with(&json!({
+            "nsfw_level": 3,
+            "features": ["COMMUNITY", "NEWS", "ANIMATED_ICON", "SOME_FUTURE_FEATURE"]
+        }));
+
+        let guild_create = guild_create(&cached(&guild));
+
+        assert_eq!(guild_create["nsfw_level"], 3);
+        assert_eq!(guild_create["features"], guild["features"]);
+    }
+
+    #[test]
+    fn channel_fields() {
+        let category = json!({
+            "id": "3001",
+            "type": 4,
+            "name": "text channels",
+            "position": 0,
+            "permission_overwrites": [],
+            "nsfw": false
+        });
+        let channel = json!({
+            "id": "3002",
+            "type": 0,
+            "name": "nsfw",
+            "position": 2,
+            "permission_overwrites": [
+                {"id": "1000", "type": 0, "allow": "0", "deny": "1024"},
+                {"id": "2000", "type": 1, "allow": "1024", "deny": "0"}
+            ],
+            "nsfw": true,
+            "topic": null,
+            "last_message_id": null,
+            "rate_limit_per_user": 0,
+            "parent_id": "3001"
+        });
+        let guild = guild_with(&json!({ "channels": [category, channel] }));
+
+        let guild_create = guild_create(&cached(&guild));
+        let sent_channel = guild_create["channels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|sent| sent["id"] == "3002")
+            .unwrap();
+
+        for field in ["permission_overwrites", "parent_id", "position", "nsfw"] {
+            assert_eq!(sent_channel[field], channel[field], "{field}");
+        }
+    }
+
+    #[test]
+    fn voice_channel_status() {
+        let voice_channel = |id: &str| {
+            json!({
+                "id": id,
+                "type": 2,
+                "name": "voice",
+                "position": 1,
+                "permission_overwrites": [],
+                "nsfw": false,
+                "bitrate": 64000,
+                "user_limit": 0,
+                "rtc_region": null
+            })
+        };
+        let guild = guild_with(&json!({
+            "channels": [voice_channel("3001"), voice_channel("3002")]
+        }));
+
+        let guilds = cached(&guild);
+        guilds.set_voice_channel_status(VoiceChannelStatus {
+            id: Id::new(3001),
+            guild_id: Id::new(1000),
+            status: Some(String::from("Playing")),
+        });
+
+        // The status is part of the channel, not an event of its own
+        let payloads: Vec<Payload> = guilds.get_guild_payloads(&mut 0).collect();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(
+            sent(&payloads[0]),
+            serde_json::to_value(&payloads[0]).unwrap()
+        );
+
+        let sent_guild = guild_create(&guilds);
+        let status = |id: &str| {
+            sent_guild["channels"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|channel| channel["id"] == id)
+                .unwrap()
+                .get("status")
+                .cloned()
+        };
+
+        assert_eq!(status("3001"), Some(json!("Playing")));
+        assert_eq!(status("3002"), None);
+
+        // An empty status clears it
+        guilds.set_voice_channel_status(VoiceChannelStatus {
+            id: Id::new(3001),
+            guild_id: Id::new(1000),
+            status: Some(String::new()),
+        });
+
+        assert!(guild_create(&guilds)["channels"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .all(|channel| channel.get("status").is_none()));
+    }
+
+    #[test]
+    fn thread_metadata() {
+        let mut thread = thread("4000", "3000");
+        thread["thread_metadata"] = json!({
+            "archived": true,
+            "auto_archive_duration": 4320,
+            "archive_timestamp": "2023-01-02T03:04:05.000000+00:00",
+            "create_timestamp": "2023-01-01T00:00:00.000000+00:00",
+            "invitable": false,
+            "locked": true
+        });
+        let guild = guild_with(&json!({ "threads": [thread] }));
+
+        let guild_create = guild_create(&cached(&guild));
+
+        assert_eq!(
+            guild_create["threads"][0]["thread_metadata"],
+            thread["thread_metadata"]
+        );
+    }
+
+    #[test]
+    fn forum_posts() {
+        let forum = json!({
+            "id": "3001",
+            "type": 15,
+            "name": "forum",
+            "position": 1,
+            "permission_overwrites": [],
+            "nsfw": false,
+            "topic": null,
+            "last_message_id": "4001",
+            "rate_limit_per_user": 0,
+            "parent_id": null,
+            "flags": 0,
+            "available_tags": [
+                {"id": "5000", "name": "bug", "moderated": false, "emoji_id": null, "emoji_name": null}
+            ],
+            "default_reaction_emoji": null,
+            "default_sort_order": null,
+            "default_forum_layout": 0
+        });
+        let mut post = thread("4001", "3001");
+        post["applied_tags"] = json!(["5000"]);
+        let guild = guild_with(&json!({
+            "channels": [forum],
+            "threads": [post]
+        }));
+
+        let guild_create = guild_create(&cached(&guild));
+        let threads = guild_create["threads"].as_array().unwrap();
+
+        assert_eq!(threads.len(), 1);
+        assert_eq!(threads[0]["id"], "4001");
+        assert_eq!(threads[0]["parent_id"], "3001");
+        assert_eq!(threads[0]["applied_tags"], json!(["5000"]));
+    }
+
+    #[test]
+    fn member_guild_avatar() {
+        let mut member = member("2001");
+        member["user"]["avatar"] = json!("fedcba9876543210fedcba9876543210");
+        member["avatar"] = json!("0123456789abcdef0123456789abcdef");
+        let guild = guild_with(&json!({ "members": [member] }));
+
+        let guild_create = guild_create(&cached(&guild));
+
+        assert_eq!(
+            guild_create["members"][0]["avatar"],
+            "0123456789abcdef0123456789abcdef"
+        );
+        assert_eq!(
+            guild_create["members"][0]["user"]["avatar"],
+            "fedcba9876543210fedcba9876543210"
+        );
+    }
+
+    #[test]
+    fn scheduled_event_lifecycle() {
+        let guilds = cached(&serde_json::from_str(GUILD).unwrap());
+        let mut scheduled_event = json!({
+            "id": "6000",
+            "guild_id": "1000",
+            "channel_id": "3000",
+            "creator_id": "2000",
+            "name": "Game night",
+            "description": null,
+            "scheduled_start_time": "2023-01-01T20:00:00.000000+00:00",
+            "scheduled_end_time": null,
+            "privacy_level": 2,
+            "status": 1,
+            "entity_type": 2,
+            "entity_id": null,
+            "entity_metadata": null,
+            "user_count": 0,
+            "image": null
+        });
+
+        guilds.update_scheduled_events(&event("GUILD_SCHEDULED_EVENT_CREATE", &scheduled_event));
+        let sent_events = guild_create(&guilds)["guild_scheduled_events"].take();
+        assert_eq!(sent_events.as_array().unwrap().len(), 1);
+        assert_eq!(sent_events[0]["status"], 1);
+
+        scheduled_event["status"] = json!(2);
+        guilds.update_scheduled_events(&event("GUILD_SCHEDULED_EVENT_UPDATE", &scheduled_event));
+        let sent_events = guild_create(&guilds)["guild_scheduled_events"].take();
+        assert_eq!(sent_events.as_array().unwrap().len(), 1);
+        assert_eq!(sent_events[0]["status"], 2);
+
+        guilds.update_scheduled_events(&event("GUILD_SCHEDULED_EVENT_DELETE", &scheduled_event));
+        assert_eq!(guild_create(&guilds)["guild_scheduled_events"], json!([]));
+    }
+
+    #[test]
+    fn auto_moderation_rule_lifecycle() {
+        let guilds = cached(&serde_json::from_str(GUILD).unwrap());
+        let rule = |id: &str, name: &str| {
+            json!({
+                "id": id,
+                "guild_id": "1000",
+                "name": name,
+                "creator_id": "2000",
+                "event_type": 1,
+                "trigger_type": 1,
+                "trigger_metadata": {"keyword_filter": ["spam"]},
+                "actions": [{"type": 1, "metadata": {"custom_message": "No spam"}}],
+                "enabled": true,
+                "exempt_roles": [],
+                "exempt_channels": ["3000"]
+            })
+        };
+        let sent_rules = |guilds: &Guilds| {
+            let mut rules = guild_create(guilds)["auto_moderation_rules"]
+                .as_array()
+                .unwrap()
+                .clone();
+            rules.sort_by_key(|rule| rule["id"].as_str().unwrap().to_owned());
+
+            rules
+        };
+
+        let mut keywords = rule("8000", "keywords");
+        let mentions = rule("8001", "mentions");
+
+        guilds.update_auto_moderation_rules(&event("AUTO_MODERATION_RULE_CREATE", &keywords));
+        guilds.update_auto_moderation_rules(&event("AUTO_MODERATION_RULE_CREATE", &mentions));
+        assert_eq!(sent_rules(&guilds), [keywords.clone(), mentions.clone()]);
+
+        keywords["enabled"] = json!(false);
+        guilds.update_auto_moderation_rules(&event("AUTO_MODERATION_RULE_UPDATE", &keywords));
+        assert_eq!(sent_rules(&guilds), [keywords.clone(), mentions.clone()]);
+
+        guilds.update_auto_moderation_rules(&event("AUTO_MODERATION_RULE_DELETE", &keywords));
+        assert_eq!(sent_rules(&guilds), [mentions]);
+
+        let guild_delete = json!({"id": "1000", "unavailable": false});
+        guilds.update_auto_moderation_rules(&event("GUILD_DELETE", &guild_delete));
+        guilds.update(event("GUILD_DELETE", &guild_delete));
+        guilds.update(event("GUILD_CREATE", &serde_json::from_str(GUILD).unwrap()));
+        assert!(sent_rules(&guilds).is_empty());
+    }
+
+    #[test]
+    fn stage_instance_lifecycle() {
+        let guilds = cached(&serde_json::from_str(GUILD).unwrap());
+        let mut stage_instance = json!({
+            "id": "7000",
+            "guild_id": "1000",
+            "channel_id": "3000",
+            "topic": "Town hall",
+            "privacy_level": 2,
+            "discoverable_disabled": false,
+            "guild_scheduled_event_id": null
+        });
+
+        guilds.update(event("STAGE_INSTANCE_CREATE", &stage_instance));
+        let sent_instances = guild_create(&guilds)["stage_instances"].take();
+        assert_eq!(sent_instances.as_array().unwrap().len(), 1);
+        assert_eq!(sent_instances[0]["topic"], "Town hall");
+
+        stage_instance["topic"] = json!("Q&A");
+        guilds.update(event("STAGE_INSTANCE_UPDATE", &stage_instance));
+        let sent_instances = guild_create(&guilds)["stage_instances"].take();
+        assert_eq!(sent_instances.as_array().unwrap().len(), 1);
+        assert_eq!(sent_instances[0]["topic"], "Q&A");
+
+        // twilight leaves out the stage instances if there are none
+        guilds.update(event("STAGE_INSTANCE_DELETE", &stage_instance));
+        assert!(guild_create(&guilds).get("stage_instances").is_none());
+    }
+
+    #[test]
+    fn role_delete() {
+        let guilds = cached(&serde_json::from_str(GUILD).unwrap());
+        let role = json!({
+            "id": "1001",
+            "name": "moderators",
+            "color": 0,
+            "hoist": true,
+            "icon": null,
+            "unicode_emoji": null,
+            "position": 1,
+            "permissions": "8",
+            "managed": false,
+            "mentionable": false,
+            "flags": 0
+        });
+
+        guilds.update(event(
+            "GUILD_ROLE_CREATE",
+            &json!({"guild_id": "1000", "role": role}),
+        ));
+        assert_eq!(guild_create(&guilds)["roles"].as_array().unwrap().len(), 2);
+
+        guilds.update(event(
+            "GUILD_ROLE_DELETE",
+            &json!({"guild_id": "1000", "role_id": "1001"}),
+        ));
+        let roles = guild_create(&guilds)["roles"].take();
+        assert_eq!(roles.as_array().unwrap().len(), 1);
+        assert_eq!(roles[0]["id"], "1000");
+    }
+
+    #[test]
+    fn member_pending() {
+        let mut member = member("2001");
+        member["pending"] = json!(true);
+        let guilds = cached(&guild_with(&json!({ "members": [member] })));
+
+        assert_eq!(guild_create(&guilds)["members"][0]["pending"], true);
+
+        // The member passed membership screening
+        member["pending"] = json!(false);
+        member["guild_id"] = json!("1000");
+        guilds.update(event("GUILD_MEMBER_UPDATE", &member));
+
+        assert_eq!(guild_create(&guilds)["members"][0]["pending"], false);
+    }
+
+    #[test]
+    fn member_timeout() {
+        let mut member = member("2001");
+        member["communication_disabled_until"] = json!("2023-01-02T03:04:05.678901+00:00");
+        let guilds = cached(&guild_with(&json!({ "members": [member] })));
+
+        assert_eq!(
+            guild_create(&guilds)["members"][0]["communication_disabled_until"],
+            "2023-01-02T03:04:05.678901+00:00"
+        );
+
+        // The timeout expired
+        member["communication_disabled_until"] = Value::Null;
+        member["guild_id"] = json!("1000");
+        guilds.update(event("GUILD_MEMBER_UPDATE", &member));
+
+        assert!(guild_create(&guilds)["members"][0]["communication_disabled_until"].is_null());
+    }
 }
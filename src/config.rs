@@ -8,22 +8,32 @@ use twilight_gateway::{EventTypeFlags, Intents};
 use twilight_model::gateway::presence::{Activity, Status};
 
 use std::{
-    env::var,
+    collections::HashMap,
+    env::{args, var},
+    ffi::OsStr,
     fmt::{Display, Formatter, Result as FmtResult},
     fs::read_to_string,
+    path::Path,
     process::exit,
     sync::LazyLock,
+    time::Duration,
 };
 
 #[derive(Deserialize)]
 pub struct Config {
     #[serde(default = "default_log_level")]
     pub log_level: String,
+    #[serde(default = "log_format_fallback")]
+    pub log_format: LogFormat,
     #[serde(default = "token_fallback")]
     pub token: String,
     pub intents: Intents,
     #[serde(default = "default_port")]
     pub port: u16,
+    /// Port to serve metrics on in addition to the main port, or `null` to
+    /// only serve them on the main port.
+    #[serde(default = "default_metrics_port")]
+    pub metrics_port: Option<u16>,
     #[serde(default)]
     pub shards: Option<u32>,
     #[serde(default)]
@@ -36,11 +46,194 @@ pub struct Config {
     pub status: Status,
     #[serde(default = "default_backpressure")]
     pub backpressure: usize,
+    #[serde(default = "default_replay_buffer")]
+    pub replay_buffer: usize,
+    #[serde(default)]
+    pub client_rate_limit: ClientRateLimit,
+    #[serde(default)]
+    pub max_clients: Option<usize>,
+    #[serde(default)]
+    pub connection_rate_limit: ConnectionRateLimit,
+    #[serde(default)]
+    pub tcp: Tcp,
     #[serde(default)]
     pub twilight_http_proxy: Option<String>,
     pub externally_accessible_url: String,
     #[serde(default)]
     pub cache: Cache,
+    #[serde(default = "auth_fallback")]
+    pub auth: AuthConfig,
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    #[serde(default = "default_ping_interval")]
+    pub ping_interval: u64,
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: usize,
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    #[serde(default)]
+    pub lagged_clients: LaggedClients,
+    #[serde(default)]
+    pub circuit_breaker: CircuitBreaker,
+    #[serde(default = "heartbeat_interval_fallback")]
+    pub heartbeat_interval: Option<u64>,
+    #[serde(default = "gateway_url_fallback")]
+    pub gateway_url: Option<String>,
+    #[serde(default)]
+    pub strip_fields: HashMap<String, Vec<String>>,
+    #[serde(default = "tls_cert_path_fallback")]
+    pub tls_cert_path: Option<String>,
+    #[serde(default = "tls_key_path_fallback")]
+    pub tls_key_path: Option<String>,
+    #[serde(default = "audit_log_fallback")]
+    pub audit_log: Option<String>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct AuthConfig {
+    /// Hex-encoded SHA-256 hashes of the tokens clients may connect with.
+    /// Authentication is disabled if this is empty. The hashes aren't
+    /// salted, so tokens should be long random strings rather than passwords.
+    #[serde(default)]
+    pub tokens: Vec<String>,
+}
+
+/// Output format of the logs.
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+/// What to do with clients that fell behind by more than `backpressure`
+/// events.
+#[derive(Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LaggedClients {
+    /// Disconnect the client with close code 1008.
+    Disconnect,
+    /// Send the missed events from the replay buffer, or skip them and send a
+    /// RESUMED if they are not buffered anymore.
+    #[default]
+    CatchUp,
+}
+
+#[derive(Deserialize)]
+pub struct CircuitBreaker {
+    /// Consecutive failed connection attempts after which a shard stops
+    /// reconnecting for the cooldown, 0 disables the circuit breaker.
+    #[serde(default = "default_failures")]
+    pub failures: u32,
+    /// Seconds to wait before trying to reconnect again.
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            failures: default_failures(),
+            cooldown: default_cooldown(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ClientRateLimit {
+    /// Maximum amount of live events sent to a client per second, 0 (the
+    /// default) disables the rate limit.
+    #[serde(default)]
+    pub events_per_second: u32,
+    /// Seconds a client may be rate limited without a break before it is
+    /// disconnected.
+    #[serde(default = "default_grace_period")]
+    pub grace_period: u64,
+}
+
+impl Default for ClientRateLimit {
+    fn default() -> Self {
+        Self {
+            events_per_second: 0,
+            grace_period: default_grace_period(),
+        }
+    }
+}
+
+/// Socket options of client connections.
+#[derive(Deserialize)]
+pub struct Tcp {
+    /// Whether to disable Nagle's algorithm, so that events are sent right away
+    /// instead of being held back to be batched with the next ones.
+    #[serde(default = "default_nodelay")]
+    pub nodelay: bool,
+    /// Seconds a connection has to be idle before keepalive probes are sent,
+    /// 0 disables keepalive.
+    #[serde(default = "default_keepalive_idle")]
+    pub keepalive_idle: u64,
+    /// Seconds between keepalive probes, the OS default if unset.
+    #[serde(default)]
+    pub keepalive_interval: Option<u64>,
+    /// Unanswered keepalive probes after which the connection is dropped, the
+    /// OS default if unset.
+    #[serde(default)]
+    pub keepalive_retries: Option<u32>,
+    /// Whether to set `SO_REUSEPORT` on the listening socket, so that several
+    /// proxy processes can listen on the same port. Only supported on Unix.
+    #[serde(default)]
+    pub reuse_port: bool,
+}
+
+impl Tcp {
+    pub const fn keepalive_idle(&self) -> Option<Duration> {
+        if self.keepalive_idle == 0 {
+            None
+        } else {
+            Some(Duration::from_secs(self.keepalive_idle))
+        }
+    }
+
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        self.keepalive_interval.map(Duration::from_secs)
+    }
+}
+
+impl Default for Tcp {
+    fn default() -> Self {
+        Self {
+            nodelay: default_nodelay(),
+            keepalive_idle: default_keepalive_idle(),
+            keepalive_interval: None,
+            keepalive_retries: None,
+            reuse_port: false,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConnectionRateLimit {
+    /// Maximum amount of connections per IP in each period, 0 (the default)
+    /// disables the rate limit.
+    #[serde(default)]
+    pub connections: u32,
+    /// Length of the period in seconds.
+    #[serde(default = "default_period")]
+    pub period: u64,
+    /// Seconds an IP is rejected for after exceeding the limit.
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+}
+
+impl Default for ConnectionRateLimit {
+    fn default() -> Self {
+        Self {
+            connections: 0,
+            period: default_period(),
+            cooldown: default_cooldown(),
+        }
+    }
 }
 
 #[derive(Deserialize, Clone)]
@@ -55,6 +248,35 @@ pub struct Cache {
     pub stickers: bool,
     pub users: bool,
     pub voice_states: bool,
+    #[serde(default)]
+    pub scheduled_events: bool,
+    #[serde(default)]
+    pub auto_moderation_rules: bool,
+    #[serde(default)]
+    pub limits: CacheLimits,
+}
+
+/// Maximum amount of cached items per shard. Once one is exceeded, the least
+/// recently active guilds are evicted with everything cached for them.
+#[derive(Deserialize, Clone, Default)]
+pub struct CacheLimits {
+    #[serde(default)]
+    pub guilds: Option<usize>,
+    #[serde(default)]
+    pub members: Option<usize>,
+    #[serde(default)]
+    pub channels: Option<usize>,
+    #[serde(default)]
+    pub roles: Option<usize>,
+}
+
+impl CacheLimits {
+    pub const fn is_unlimited(&self) -> bool {
+        self.guilds.is_none()
+            && self.members.is_none()
+            && self.channels.is_none()
+            && self.roles.is_none()
+    }
 }
 
 impl Default for Cache {
@@ -70,7 +292,54 @@ impl Default for Cache {
             stickers: false,
             users: false,
             voice_states: false,
+            scheduled_events: false,
+            auto_moderation_rules: false,
+            limits: CacheLimits::default(),
+        }
+    }
+}
+
+impl Cache {
+    /// Only cache the resources from a comma-separated list like
+    /// `channels,roles`, returning the first unknown resource if any.
+    fn set_resources(&mut self, resources: &str) -> Result<(), String> {
+        *self = Self {
+            channels: false,
+            presences: false,
+            emojis: false,
+            current_member: false,
+            members: false,
+            roles: false,
+            stage_instances: false,
+            stickers: false,
+            users: false,
+            voice_states: false,
+            scheduled_events: false,
+            auto_moderation_rules: false,
+            limits: self.limits.clone(),
+        };
+
+        for resource in resources.split(',').map(str::trim) {
+            match resource {
+                "channels" => self.channels = true,
+                "presences" => self.presences = true,
+                "emojis" => self.emojis = true,
+                "current_member" => self.current_member = true,
+                "members" => self.members = true,
+                "roles" => self.roles = true,
+                "stage_instances" => self.stage_instances = true,
+                "stickers" => self.stickers = true,
+                "users" => self.users = true,
+                "voice_states" => self.voice_states = true,
+                "scheduled_events" => self.scheduled_events = true,
+                "auto_moderation_rules" => self.auto_moderation_rules = true,
+                // Guilds are always cached
+                "guilds" | "" => {}
+                _ => return Err(resource.to_string()),
+            }
         }
+
+        Ok(())
     }
 }
 
@@ -86,6 +355,11 @@ impl From<Cache> for EventTypeFlags {
             flags |= Self::MEMBER_ADD | Self::MEMBER_REMOVE | Self::MEMBER_UPDATE;
         }
 
+        // Chunks are large, so only parse them if they are of any use
+        if cache.members {
+            flags |= Self::MEMBER_CHUNK;
+        }
+
         if cache.roles {
             flags |= Self::ROLE_CREATE | Self::ROLE_DELETE | Self::ROLE_UPDATE;
         }
@@ -97,6 +371,7 @@ impl From<Cache> for EventTypeFlags {
                 | Self::THREAD_CREATE
                 | Self::THREAD_DELETE
                 | Self::THREAD_LIST_SYNC
+                | Self::THREAD_MEMBERS_UPDATE
                 | Self::THREAD_UPDATE;
         }
 
@@ -108,6 +383,10 @@ impl From<Cache> for EventTypeFlags {
             flags |= Self::GUILD_EMOJIS_UPDATE;
         }
 
+        if cache.stickers {
+            flags |= Self::GUILD_STICKERS_UPDATE;
+        }
+
         if cache.stage_instances {
             flags |= Self::STAGE_INSTANCE_CREATE
                 | Self::STAGE_INSTANCE_DELETE
@@ -122,6 +401,18 @@ impl From<Cache> for EventTypeFlags {
             flags |= Self::USER_UPDATE;
         }
 
+        if cache.scheduled_events {
+            flags |= Self::GUILD_SCHEDULED_EVENT_CREATE
+                | Self::GUILD_SCHEDULED_EVENT_DELETE
+                | Self::GUILD_SCHEDULED_EVENT_UPDATE;
+        }
+
+        if cache.auto_moderation_rules {
+            flags |= Self::AUTO_MODERATION_RULE_CREATE
+                | Self::AUTO_MODERATION_RULE_DELETE
+                | Self::AUTO_MODERATION_RULE_UPDATE;
+        }
+
         flags
     }
 }
@@ -178,10 +469,29 @@ fn default_log_level() -> String {
     String::from("info")
 }
 
+fn log_format_from_env() -> Option<LogFormat> {
+    match var("LOG_FORMAT").ok()?.as_str() {
+        "full" => Some(LogFormat::Full),
+        "compact" => Some(LogFormat::Compact),
+        "pretty" => Some(LogFormat::Pretty),
+        "json" => Some(LogFormat::Json),
+        _ => None,
+    }
+}
+
+fn log_format_fallback() -> LogFormat {
+    log_format_from_env().unwrap_or(LogFormat::Full)
+}
+
 const fn default_port() -> u16 {
     7878
 }
 
+#[allow(clippy::unnecessary_wraps)]
+const fn default_metrics_port() -> Option<u16> {
+    Some(9090)
+}
+
 fn token_fallback() -> String {
     if let Ok(token) = var("TOKEN") {
         token
@@ -191,46 +501,217 @@ fn token_fallback() -> String {
     }
 }
 
+fn heartbeat_interval_fallback() -> Option<u64> {
+    var("PROXY_HEARTBEAT_INTERVAL_MS")
+        .ok()
+        .and_then(|interval| interval.parse().ok())
+}
+
+fn gateway_url_fallback() -> Option<String> {
+    var("GATEWAY_URL").ok()
+}
+
+fn tls_cert_path_fallback() -> Option<String> {
+    var("TLS_CERT_PATH").ok()
+}
+
+fn tls_key_path_fallback() -> Option<String> {
+    var("TLS_KEY_PATH").ok()
+}
+
+fn audit_log_fallback() -> Option<String> {
+    var("AUDIT_LOG").ok()
+}
+
+fn auth_from_env() -> Option<AuthConfig> {
+    let tokens = var("AUTH_TOKENS")
+        .ok()?
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(ToString::to_string)
+        .collect();
+
+    Some(AuthConfig { tokens })
+}
+
+fn auth_fallback() -> AuthConfig {
+    auth_from_env().unwrap_or_default()
+}
+
 const fn default_status() -> Status {
     Status::Online
 }
 
-const fn default_backpressure() -> usize {
-    100
+fn backpressure_from_env() -> Option<usize> {
+    var("BROADCAST_CAPACITY")
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+}
+
+fn default_backpressure() -> usize {
+    backpressure_from_env().unwrap_or(100)
+}
+
+const fn default_replay_buffer() -> usize {
+    250
+}
+
+const fn default_grace_period() -> u64 {
+    10
+}
+
+const fn default_failures() -> u32 {
+    5
+}
+
+const fn default_period() -> u64 {
+    10
+}
+
+const fn default_cooldown() -> u64 {
+    60
+}
+
+const fn default_nodelay() -> bool {
+    true
+}
+
+const fn default_keepalive_idle() -> u64 {
+    60
+}
+
+const fn default_shutdown_timeout() -> u64 {
+    10
+}
+
+const fn default_ping_interval() -> u64 {
+    45
+}
+
+const fn default_max_message_size() -> usize {
+    4 * 1024 * 1024
+}
+
+const fn default_max_frame_size() -> usize {
+    64 * 1024
 }
 
 pub enum Error {
     InvalidConfig(JsonError),
+    InvalidToml(toml::de::Error),
+    InvalidYaml(serde_yaml::Error),
     NotFound(String),
+    UnknownCacheResource(String),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
         match self {
             Self::InvalidConfig(s) => s.fmt(f),
+            Self::InvalidToml(s) => s.fmt(f),
+            Self::InvalidYaml(s) => s.fmt(f),
             Self::NotFound(s) => f.write_fmt(format_args!("File {s} not found or access denied")),
+            Self::UnknownCacheResource(s) => {
+                f.write_fmt(format_args!("Unknown resource {s} in CACHE_RESOURCES"))
+            }
         }
     }
 }
 
 #[cfg(feature = "simd-json")]
-pub fn load(path: &str) -> Result<Config, Error> {
-    let mut content = read_to_string(path).map_err(|_| Error::NotFound(path.to_string()))?;
-    let config = unsafe { simd_json::from_str(&mut content) }.map_err(Error::InvalidConfig)?;
-
-    Ok(config)
+fn from_json(mut content: String) -> Result<Config, JsonError> {
+    unsafe { simd_json::from_str(&mut content) }
 }
 
 #[cfg(not(feature = "simd-json"))]
+fn from_json(content: String) -> Result<Config, JsonError> {
+    serde_json::from_str(&content)
+}
+
+/// Let the environment variables take precedence over the config file.
+fn override_from_env(config: &mut Config) -> Result<(), Error> {
+    if let Ok(token) = var("TOKEN") {
+        config.token = token;
+    }
+
+    if let Some(log_format) = log_format_from_env() {
+        config.log_format = log_format;
+    }
+
+    if let Some(backpressure) = backpressure_from_env() {
+        config.backpressure = backpressure;
+    }
+
+    if let Some(auth) = auth_from_env() {
+        config.auth = auth;
+    }
+
+    if let Some(heartbeat_interval) = heartbeat_interval_fallback() {
+        config.heartbeat_interval = Some(heartbeat_interval);
+    }
+
+    if let Some(gateway_url) = gateway_url_fallback() {
+        config.gateway_url = Some(gateway_url);
+    }
+
+    if let Some(tls_cert_path) = tls_cert_path_fallback() {
+        config.tls_cert_path = Some(tls_cert_path);
+    }
+
+    if let Some(tls_key_path) = tls_key_path_fallback() {
+        config.tls_key_path = Some(tls_key_path);
+    }
+
+    if let Some(audit_log) = audit_log_fallback() {
+        config.audit_log = Some(audit_log);
+    }
+
+    if let Ok(resources) = var("CACHE_RESOURCES") {
+        config
+            .cache
+            .set_resources(&resources)
+            .map_err(Error::UnknownCacheResource)?;
+    }
+
+    Ok(())
+}
+
+/// Load the config from a JSON, TOML or YAML file, depending on its extension.
 pub fn load(path: &str) -> Result<Config, Error> {
     let content = read_to_string(path).map_err(|_| Error::NotFound(path.to_string()))?;
-    let config = serde_json::from_str(&content).map_err(Error::InvalidConfig)?;
+
+    let mut config = match Path::new(path).extension().and_then(OsStr::to_str) {
+        Some("toml") => toml::from_str(&content).map_err(Error::InvalidToml)?,
+        Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(Error::InvalidYaml)?,
+        _ => from_json(content).map_err(Error::InvalidConfig)?,
+    };
+
+    override_from_env(&mut config)?;
 
     Ok(config)
 }
 
+/// Path of the config file, from the `--config` argument, the `CONFIG_PATH`
+/// environment variable or `config.json` otherwise.
+fn path() -> String {
+    let mut args = args().skip(1);
+
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            if let Some(path) = args.next() {
+                return path;
+            }
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            return path.to_string();
+        }
+    }
+
+    var("CONFIG_PATH").unwrap_or_else(|_| String::from("config.json"))
+}
+
 pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
-    match load("config.json") {
+    match load(&path()) {
         Ok(config) => config,
         Err(err) => {
             // Avoid panicking
@@ -1,21 +1,94 @@
 use itoa::Buffer;
+#[cfg(not(feature = "simd-json"))]
+use serde_json::Value as OwnedValue;
 #[cfg(feature = "simd-json")]
-use simd_json::Mutable;
-use tokio::{sync::broadcast, time::Instant};
-use tracing::{debug, trace};
-use twilight_gateway::{parse, ConnectionStatus, Event, EventTypeFlags, Message, Shard};
-use twilight_model::gateway::event::GatewayEvent as TwilightGatewayEvent;
+use simd_json::{Mutable, OwnedValue};
+use tokio::{
+    sync::broadcast,
+    time::{sleep, Instant},
+};
+use tokio_tungstenite::tungstenite::protocol::{frame::coding::CloseCode, CloseFrame};
+use tracing::{debug, field, trace, trace_span, warn, Span};
+use twilight_gateway::{
+    error::ReceiveMessageErrorType, parse, ConnectionStatus, Event, EventTypeFlags, Message, Shard,
+};
+use twilight_model::{
+    gateway::event::GatewayEvent as TwilightGatewayEvent,
+    id::{marker::GuildMarker, Id},
+};
 
-use std::{sync::Arc, time::Duration};
+use std::{
+    ops::Range,
+    sync::{atomic::Ordering, Arc, OnceLock},
+    time::Duration,
+};
 
 use crate::{
+    cache::CacheUpdateStats,
     config::CONFIG,
     deserializer::{EventTypeInfo, GatewayEvent, SequenceInfo},
-    model::Ready,
-    state::Shard as ShardState,
+    encoding::EtfTemplate,
+    intents::find_guild_id,
+    model::{Dispatch, GuildCreateExtras, Hello, JsonObject, Ready, VoiceChannelStatusUpdate},
+    reconnect::{Backoff, CircuitState},
+    server::INVALID_SESSION,
+    state::{Shard as ShardState, ShardInfo},
 };
 
-pub type BroadcastMessage = (String, Option<SequenceInfo>);
+/// An event relayed to clients, alongside the span it was received in, so
+/// that client delivery can be traced back to it, its index in the shard's
+/// replay buffer and when it was received.
+pub type BroadcastMessage = (Arc<RelayedEvent>, Option<Span>, u64, Instant);
+
+/// A payload relayed to clients with what they filter it by, which is found
+/// once for all of them.
+///
+/// The payload is shared by all clients, which copy it when rewriting the
+/// sequence number, since every message sent to a client owns its payload.
+/// Clients that use ETF share its encoding the same way.
+pub struct RelayedEvent {
+    pub payload: String,
+    pub sequence: Option<SequenceInfo>,
+    /// Position of the event type in the payload.
+    event_type: Option<Range<usize>>,
+    /// ID of the guild the event belongs to.
+    pub guild_id: Option<Id<GuildMarker>>,
+    /// Encoded by the first client that uses ETF.
+    etf: OnceLock<Option<EtfTemplate>>,
+}
+
+impl RelayedEvent {
+    pub fn new(payload: String, guild_id: Option<Id<GuildMarker>>) -> Self {
+        let (sequence, event_type) =
+            GatewayEvent::from_json(&payload).map_or((None, None), |event| {
+                let (_, sequence, event_type) = event.into_parts();
+
+                (sequence, event_type.map(|EventTypeInfo(_, range)| range))
+            });
+
+        Self {
+            payload,
+            sequence,
+            event_type,
+            guild_id,
+            etf: OnceLock::new(),
+        }
+    }
+
+    /// The payload encoded as ETF, which is only done once for all clients.
+    pub fn etf(&self) -> Option<&EtfTemplate> {
+        self.etf
+            .get_or_init(|| EtfTemplate::new(&self.payload))
+            .as_ref()
+    }
+
+    /// Type of the event, if it is a dispatch.
+    pub fn event_type(&self) -> Option<&str> {
+        self.event_type
+            .clone()
+            .map(|event_type| &self.payload[event_type])
+    }
+}
 
 const TEN_SECONDS: Duration = Duration::from_secs(10);
 
@@ -34,6 +107,14 @@ pub async fn events(
 
     let mut last_metrics_update = Instant::now();
 
+    let mut backoff = Backoff::new(&CONFIG.circuit_breaker);
+
+    // Last sequence number received, to detect missed events
+    let mut last_sequence = None;
+
+    // Reason of the last close frame from Discord, for telling clients why the shard stopped
+    let mut last_close_reason = None;
+
     let event_type_flags: EventTypeFlags = CONFIG.cache.clone().into();
 
     loop {
@@ -44,6 +125,7 @@ pub async fn events(
             let latencies = shard.latency().recent();
             let info = shard.status();
             update_shard_statistics(&shard_id_str, &shard_state, info, latencies);
+            update_shard_info(&shard, &shard_state, backoff.circuit());
             last_metrics_update = now;
         }
 
@@ -53,27 +135,134 @@ pub async fn events(
                 tracing::error!("Error receiving message: {e}");
 
                 if e.is_fatal() {
+                    if let ConnectionStatus::FatallyClosed { close_code } = shard.status() {
+                        close_clients(&shard_state, *close_code as u16, last_close_reason.take());
+                    }
+
+                    update_shard_info(&shard, &shard_state, backoff.circuit());
                     break;
                 }
 
+                // Don't reconnect right away if the connection was lost
+                if matches!(
+                    e.kind(),
+                    ReceiveMessageErrorType::Io | ReceiveMessageErrorType::Reconnect
+                ) {
+                    let delay = backoff.next_delay();
+
+                    if backoff.circuit() == CircuitState::Open {
+                        warn!(
+                            "[Shard {shard_id}] Reconnecting failed too often, waiting {delay:?}"
+                        );
+                    } else {
+                        debug!("[Shard {shard_id}] Reconnecting in {delay:?}");
+                    }
+
+                    update_shard_info(&shard, &shard_state, backoff.circuit());
+                    sleep(delay).await;
+                    backoff.reconnecting();
+                    update_shard_info(&shard, &shard_state, backoff.circuit());
+                }
+
                 continue;
             }
         };
 
+        if let Message::Close(Some(frame)) = &msg {
+            warn!(
+                "[Shard {shard_id}] Discord closed the connection with code {}: {}",
+                frame.code(),
+                frame.reason()
+            );
+            last_close_reason = Some(frame.reason().to_owned());
+        }
+
         // NOTE: payload cannot be modified because we have to do optional event parsing
         // later. Don't use simd_json::from_str on it because that will make the data useless.
         // Instead, clone it before mutating.
         if let Message::Text(payload) = msg {
-            let Some(event ) = GatewayEvent::from_json(&payload) else {
+            let received_at = Instant::now();
+
+            let Some(event) = GatewayEvent::from_json(&payload) else {
                 tracing::error!("Failed to deserialize gateway event");
                 continue;
             };
 
             let (op, sequence, event_type) = event.into_parts();
 
+            if let Some(SequenceInfo(seq, _)) = &sequence {
+                let seq = *seq;
+                // Sequence numbers start again at 1 with every new session
+                let new_session = matches!(&event_type, Some(EventTypeInfo("READY", _)));
+
+                if let Some(last_seq) = last_sequence.filter(|_| !new_session) {
+                    if seq > last_seq + 1 {
+                        warn!(
+                            "[Shard {shard_id}] Missed {} events after sequence {last_seq}",
+                            seq - last_seq - 1
+                        );
+                        metrics::increment_counter!("gateway_sequence_gaps_total", "shard" => shard_id_str.clone());
+                    }
+                }
+
+                last_sequence = Some(seq);
+            }
+
+            if op.0 == 10 {
+                #[cfg(feature = "simd-json")]
+                let hello: Result<Hello, _> = unsafe { simd_json::from_str(&mut payload.clone()) };
+                #[cfg(not(feature = "simd-json"))]
+                let hello: Result<Hello, _> = serde_json::from_str(&payload);
+
+                if let Ok(hello) = hello {
+                    shard_state
+                        .heartbeat_interval
+                        .store(hello.d.heartbeat_interval, Ordering::Relaxed);
+                }
+            }
+
             if let Some(EventTypeInfo(event_name, _)) = event_type {
                 metrics::increment_counter!("gateway_shard_events", "shard" => shard_id_str.clone(), "event_type" => event_name.to_owned());
 
+                let guild_id = find_guild_id(&payload, event_name);
+
+                if !CONFIG.cache.limits.is_unlimited() {
+                    if let Some(guild_id) = guild_id {
+                        if event_name == "GUILD_DELETE" {
+                            shard_state.guilds.forget(guild_id);
+                        } else {
+                            shard_state.guilds.touch(guild_id);
+                        }
+                    }
+                }
+
+                if event_name == "GUILD_CREATE" && CONFIG.cache.scheduled_events {
+                    // twilight doesn't cache scheduled events, so get them from the raw payload
+                    #[cfg(feature = "simd-json")]
+                    let extras: Result<GuildCreateExtras, _> =
+                        unsafe { simd_json::from_str(&mut payload.clone()) };
+                    #[cfg(not(feature = "simd-json"))]
+                    let extras: Result<GuildCreateExtras, _> = serde_json::from_str(&payload);
+
+                    if let Ok(extras) = extras {
+                        shard_state.guilds.set_guild_extras(extras.d);
+                    }
+                }
+
+                if event_name == "VOICE_CHANNEL_STATUS_UPDATE" && CONFIG.cache.channels {
+                    // twilight doesn't know this event yet, so parse it ourselves
+                    #[cfg(feature = "simd-json")]
+                    let update: Result<VoiceChannelStatusUpdate, _> =
+                        unsafe { simd_json::from_str(&mut payload.clone()) };
+                    #[cfg(not(feature = "simd-json"))]
+                    let update: Result<VoiceChannelStatusUpdate, _> =
+                        serde_json::from_str(&payload);
+
+                    if let Ok(update) = update {
+                        shard_state.guilds.set_voice_channel_status(update.d);
+                    }
+                }
+
                 if event_name == "READY" {
                     // Use the raw JSON from READY to create a new blank READY
 
@@ -90,32 +279,64 @@ pub async fn events(
                         }
                     }
 
-                    // Override resume_gateway_url with the external URI of the proxy
-                    ready.d.insert(
-                        String::from("resume_gateway_url"),
-                        CONFIG.externally_accessible_url.clone().into(),
-                    );
-
                     // We don't care if it was already set
                     // since this data is timeless
                     shard_state.ready.set_ready(ready.d);
+                    backoff.connected();
+                    update_shard_info(&shard, &shard_state, backoff.circuit());
                     is_ready = true;
                 } else if event_name == "RESUMED" {
+                    backoff.connected();
+                    update_shard_info(&shard, &shard_state, backoff.circuit());
                     is_ready = true;
                 } else if op.0 == 0 && is_ready {
                     // We only want to relay dispatchable events, not RESUMEs and not READY
                     // because we fake a READY event
-                    let payload_copy = payload.clone();
-                    trace!("[Shard {shard_id}] Sending payload to clients: {payload_copy:?}",);
+                    let span = trace_span!(
+                        "relay_event",
+                        shard_id,
+                        event_type = event_name,
+                        client_count = field::Empty,
+                    );
+                    let event = Arc::new(RelayedEvent::new(
+                        strip_fields(&payload, event_name).unwrap_or_else(|| payload.clone()),
+                        guild_id,
+                    ));
+                    span.in_scope(|| {
+                        trace!(
+                            "[Shard {shard_id}] Sending payload to clients: {:?}",
+                            event.payload
+                        );
+                    });
 
-                    let _res = broadcast_tx.send((payload_copy, sequence));
+                    let client_count = shard_state.replay.push(event.clone(), |index| {
+                        broadcast_tx
+                            .send((event, Some(span.clone()), index, received_at))
+                            .unwrap_or(0)
+                    });
+                    span.record("client_count", client_count);
                 }
             }
 
             if let Ok(Some(event)) = parse(payload, event_type_flags) {
                 match event {
                     TwilightGatewayEvent::Dispatch(_, event) => {
-                        shard_state.guilds.update(Event::from(event));
+                        let event = Event::from(event);
+                        shard_state.guilds.update_scheduled_events(&event);
+                        shard_state.guilds.update_auto_moderation_rules(&event);
+                        shard_state.guilds.update_thread_member_counts(&event);
+                        shard_state.guilds.update_voice_channel_statuses(&event);
+                        update_replacement_statistics(&shard_id_str, &event);
+
+                        let stats = shard_state.guilds.update_with_stats(event);
+
+                        update_cache_statistics(&shard_id_str, &stats);
+
+                        if !CONFIG.cache.limits.is_unlimited() {
+                            for resource in shard_state.guilds.enforce_limits() {
+                                metrics::increment_counter!("gateway_cache_evictions", "shard" => shard_id_str.clone(), "resource" => resource);
+                            }
+                        }
                     }
                     TwilightGatewayEvent::InvalidateSession(can_resume) => {
                         debug!("[Shard {shard_id}] Session invalidated, resumable: {can_resume}");
@@ -123,6 +344,13 @@ pub async fn events(
                             // We can only reset the READY state if we know that we will get a new READY,
                             // which is the case if we can not resume.
                             shard_state.ready.set_not_ready();
+
+                            // Clients have to identify again to get the new READY and guilds
+                            let event =
+                                Arc::new(RelayedEvent::new(INVALID_SESSION.to_owned(), None));
+                            shard_state.replay.push(event.clone(), |index| {
+                                let _res = broadcast_tx.send((event, None, index, received_at));
+                            });
                         }
                         // Suspend sending events to clients until READY or RESUMED are received.
                         is_ready = false;
@@ -134,6 +362,59 @@ pub async fn events(
     }
 }
 
+/// Disconnect the clients of a shard that Discord closed the connection of
+/// with a code that does not allow reconnecting, like 4004 for an invalid
+/// token or 4014 for disallowed intents.
+///
+/// Clients are closed with 1008, since they can't do anything about it
+/// either, and told the original code and reason.
+fn close_clients(shard_state: &ShardState, code: u16, reason: Option<String>) {
+    let shard_id = shard_state.id;
+
+    warn!(
+        "[Shard {shard_id}] Connection was closed with code {code} for good, disconnecting clients"
+    );
+
+    let reason = reason.map_or_else(
+        || format!("Discord closed the shard with {code}"),
+        |reason| format!("Discord closed the shard with {code}: {reason}"),
+    );
+    let frame = CloseFrame {
+        code: CloseCode::Policy,
+        reason: reason.into(),
+    };
+
+    shard_state.closed.send_replace(Some(frame));
+}
+
+fn update_shard_info(shard: &Shard, shard_state: &ShardState, circuit: CircuitState) {
+    let status = match shard.status() {
+        ConnectionStatus::Connected => "connected",
+        ConnectionStatus::Disconnected { .. } => "disconnected",
+        ConnectionStatus::Identifying => "identifying",
+        ConnectionStatus::Resuming => "resuming",
+        ConnectionStatus::FatallyClosed { .. } => "fatally_closed",
+    };
+
+    let latency_ms = shard
+        .latency()
+        .recent()
+        .first()
+        .map(|latency| latency.as_secs_f64() * 1000.0);
+    let session_id = shard.session().map(|session| session.id().to_owned());
+
+    *shard_state.info.write().unwrap() = ShardInfo {
+        id: shard_state.id,
+        status,
+        latency_ms,
+        session_id,
+        circuit,
+        api_version: shard_state.ready.api_version(),
+    };
+
+    metrics::gauge!("gateway_shard_circuit", circuit.as_f64(), "shard" => shard_state.id.to_string());
+}
+
 pub fn update_shard_statistics(
     shard_id: &str,
     shard_state: &Arc<ShardState>,
@@ -167,7 +448,94 @@ pub fn update_shard_statistics(
     metrics::gauge!("gateway_cache_presences", stats.presences() as f64, "shard" => shard_id.to_string());
     metrics::gauge!("gateway_cache_channels", stats.channels() as f64, "shard" => shard_id.to_string());
     metrics::gauge!("gateway_cache_roles", stats.roles() as f64, "shard" => shard_id.to_string());
+    metrics::gauge!("gateway_cache_scheduled_events", shard_state.guilds.scheduled_event_count() as f64, "shard" => shard_id.to_string());
+    metrics::gauge!("gateway_cache_threads", shard_state.guilds.thread_count() as f64, "shard" => shard_id.to_string());
     metrics::gauge!("gateway_cache_unavailable_guilds", stats.unavailable_guilds() as f64, "shard" => shard_id.to_string());
     metrics::gauge!("gateway_cache_users", stats.users() as f64, "shard" => shard_id.to_string());
     metrics::gauge!("gateway_cache_voice_states", stats.voice_states() as f64, "shard" => shard_id.to_string());
 }
+
+/// Remove the fields configured in `strip_fields` from the data of an event.
+///
+/// Returns [`None`] if there are no fields to remove for this event type, so
+/// that events are only parsed and serialized again if necessary.
+pub fn strip_fields(payload: &str, event_type: &str) -> Option<String> {
+    let fields: Vec<&String> = CONFIG
+        .strip_fields
+        .get(event_type)
+        .into_iter()
+        .chain(CONFIG.strip_fields.get("*"))
+        .flatten()
+        .collect();
+
+    if fields.is_empty() {
+        return None;
+    }
+
+    #[cfg(feature = "simd-json")]
+    let mut event: Dispatch = unsafe { simd_json::from_str(&mut payload.to_owned()) }.ok()?;
+    #[cfg(not(feature = "simd-json"))]
+    let mut event: Dispatch = serde_json::from_str(payload).ok()?;
+
+    for field in fields {
+        remove_field(&mut event.d, field);
+    }
+
+    #[cfg(feature = "simd-json")]
+    let payload = simd_json::to_string(&event);
+    #[cfg(not(feature = "simd-json"))]
+    let payload = serde_json::to_string(&event);
+
+    payload.ok()
+}
+
+/// Remove a field from the data of an event, which is nested in objects if its
+/// path contains dots, like `user.email`.
+fn remove_field(data: &mut JsonObject, path: &str) {
+    let Some((parents, field)) = path.rsplit_once('.') else {
+        data.remove(path);
+        return;
+    };
+
+    let mut keys = parents.split('.');
+    let mut parent = keys.next().and_then(|key| data.get_mut(key));
+
+    for key in keys {
+        parent = parent
+            .and_then(OwnedValue::as_object_mut)
+            .and_then(|object| object.get_mut(key));
+    }
+
+    if let Some(object) = parent.and_then(OwnedValue::as_object_mut) {
+        object.remove(field);
+    }
+}
+
+/// Count events that replace a whole list of cached items of a guild.
+fn update_replacement_statistics(shard_id: &str, event: &Event) {
+    let name = match event {
+        Event::GuildEmojisUpdate(_) => "gateway_cache_emoji_updates",
+        Event::GuildStickersUpdate(_) => "gateway_cache_sticker_updates",
+        _ => return,
+    };
+
+    metrics::increment_counter!(name, "shard" => shard_id.to_string());
+}
+
+fn update_cache_statistics(shard_id: &str, stats: &CacheUpdateStats) {
+    if stats.guilds_added > 0 {
+        metrics::counter!("gateway_cache_guilds_added", stats.guilds_added.into(), "shard" => shard_id.to_string());
+    }
+
+    if stats.guilds_removed > 0 {
+        metrics::counter!("gateway_cache_guilds_removed", stats.guilds_removed.into(), "shard" => shard_id.to_string());
+    }
+
+    if stats.members_added > 0 {
+        metrics::counter!("gateway_cache_members_added", stats.members_added.into(), "shard" => shard_id.to_string());
+    }
+
+    if stats.channels_added > 0 {
+        metrics::counter!("gateway_cache_channels_added", stats.channels_added.into(), "shard" => shard_id.to_string());
+    }
+}
@@ -0,0 +1,639 @@
+#[cfg(not(feature = "simd-json"))]
+use serde_json::{to_vec, Value as OwnedValue};
+#[cfg(feature = "simd-json")]
+use simd_json::{to_vec, OwnedValue, StaticNode};
+use tokio_tungstenite::tungstenite::Message;
+
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use crate::model::JsonObject;
+
+/// Version byte every ETF payload is prefixed with.
+const FORMAT_VERSION: u8 = 131;
+
+/// How deeply lists, tuples and maps may be nested in payloads from clients,
+/// so that decoding them can't overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+const NEW_FLOAT_EXT: u8 = 70;
+const SMALL_INTEGER_EXT: u8 = 97;
+const INTEGER_EXT: u8 = 98;
+const ATOM_EXT: u8 = 100;
+const SMALL_TUPLE_EXT: u8 = 104;
+const LARGE_TUPLE_EXT: u8 = 105;
+const NIL_EXT: u8 = 106;
+const STRING_EXT: u8 = 107;
+const LIST_EXT: u8 = 108;
+const BINARY_EXT: u8 = 109;
+const SMALL_BIG_EXT: u8 = 110;
+const SMALL_ATOM_EXT: u8 = 115;
+const MAP_EXT: u8 = 116;
+const ATOM_UTF8_EXT: u8 = 118;
+const SMALL_ATOM_UTF8_EXT: u8 = 119;
+
+/// Encoding of the payloads exchanged with a client.
+///
+/// Payloads are always JSON internally, clients that requested ETF get them
+/// converted right before sending and their own payloads converted back to
+/// JSON right after receiving.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Etf,
+}
+
+impl Encoding {
+    /// Parse the encoding from the query string of the gateway URL.
+    pub fn from_query(query: Option<&str>) -> Self {
+        if query.map_or(false, |q| q.split('&').any(|pair| pair == "encoding=etf")) {
+            Self::Etf
+        } else {
+            Self::Json
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Etf => "etf",
+        }
+    }
+
+    /// Convert an outgoing JSON message to this encoding, binary messages are
+    /// already encoded.
+    pub fn encode(self, message: Message) -> Option<Message> {
+        match (self, message) {
+            (Self::Etf, Message::Text(json)) => json_to_etf(json.into_bytes()).map(Message::Binary),
+            (_, message) => Some(message),
+        }
+    }
+
+    /// Convert an incoming payload in this encoding to JSON.
+    pub fn decode(self, data: Vec<u8>) -> Option<Vec<u8>> {
+        match self {
+            Self::Json => Some(data),
+            Self::Etf => etf_to_json(&data),
+        }
+    }
+}
+
+impl Display for Encoding {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        f.write_str(self.name())
+    }
+}
+
+/// An event encoded as ETF once for all clients, with room for each client's
+/// own sequence number.
+///
+/// The sequence number is always written as an [`INTEGER_EXT`], so that it
+/// can be replaced without encoding the event again.
+pub struct EtfTemplate {
+    etf: Vec<u8>,
+    /// Position of the sequence number's 4 bytes.
+    sequence_offset: Option<usize>,
+}
+
+impl EtfTemplate {
+    pub fn new(json: &str) -> Option<Self> {
+        let value = parse(json.as_bytes().to_vec())?;
+
+        let mut etf = Vec::with_capacity(json.len());
+        etf.push(FORMAT_VERSION);
+        let mut sequence_offset = None;
+
+        if let Some((len, entries)) = object_entries(&value) {
+            write_map_header(&mut etf, len);
+
+            for (key, value) in entries {
+                write_atom(&mut etf, key);
+
+                if key == "s" && is_integer(value) {
+                    etf.push(INTEGER_EXT);
+                    sequence_offset = Some(etf.len());
+                    etf.extend_from_slice(&[0; 4]);
+                } else {
+                    write_value(&mut etf, value);
+                }
+            }
+        } else {
+            write_value(&mut etf, &value);
+        }
+
+        Some(Self {
+            etf,
+            sequence_offset,
+        })
+    }
+
+    /// A copy of the event with this sequence number, or [`None`] if it does
+    /// not fit into an [`INTEGER_EXT`].
+    pub fn with_sequence(&self, sequence: Option<usize>) -> Option<Vec<u8>> {
+        let mut etf = self.etf.clone();
+
+        if let (Some(offset), Some(sequence)) = (self.sequence_offset, sequence) {
+            let sequence = i32::try_from(sequence).ok()?;
+            etf[offset..offset + 4].copy_from_slice(&sequence.to_be_bytes());
+        }
+
+        Some(etf)
+    }
+}
+
+#[cfg(feature = "simd-json")]
+fn parse(mut json: Vec<u8>) -> Option<OwnedValue> {
+    simd_json::to_owned_value(&mut json).ok()
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn parse(json: Vec<u8>) -> Option<OwnedValue> {
+    serde_json::from_slice(&json).ok()
+}
+
+fn json_to_etf(json: Vec<u8>) -> Option<Vec<u8>> {
+    let mut output = Vec::with_capacity(json.len());
+    let value = parse(json)?;
+
+    output.push(FORMAT_VERSION);
+    write_value(&mut output, &value);
+
+    Some(output)
+}
+
+/// The length and entries of an object.
+#[cfg(feature = "simd-json")]
+fn object_entries(
+    value: &OwnedValue,
+) -> Option<(usize, impl Iterator<Item = (&String, &OwnedValue)>)> {
+    match value {
+        OwnedValue::Object(object) => Some((object.len(), object.iter())),
+        _ => None,
+    }
+}
+
+/// The length and entries of an object.
+#[cfg(not(feature = "simd-json"))]
+fn object_entries(
+    value: &OwnedValue,
+) -> Option<(usize, impl Iterator<Item = (&String, &OwnedValue)>)> {
+    match value {
+        OwnedValue::Object(object) => Some((object.len(), object.iter())),
+        _ => None,
+    }
+}
+
+#[cfg(feature = "simd-json")]
+const fn is_integer(value: &OwnedValue) -> bool {
+    matches!(
+        value,
+        OwnedValue::Static(StaticNode::I64(_) | StaticNode::U64(_))
+    )
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn is_integer(value: &OwnedValue) -> bool {
+    value.is_i64() || value.is_u64()
+}
+
+fn etf_to_json(etf: &[u8]) -> Option<Vec<u8>> {
+    let mut decoder = Decoder { input: etf };
+
+    if decoder.read_u8()? != FORMAT_VERSION {
+        return None;
+    }
+
+    to_vec(&decoder.read_value(0)?).ok()
+}
+
+#[cfg(feature = "simd-json")]
+fn write_value(output: &mut Vec<u8>, value: &OwnedValue) {
+    match value {
+        OwnedValue::Static(StaticNode::Null) => write_atom(output, "nil"),
+        OwnedValue::Static(StaticNode::Bool(b)) => {
+            write_atom(output, if *b { "true" } else { "false" })
+        }
+        OwnedValue::Static(StaticNode::I64(i)) => write_integer(output, *i),
+        OwnedValue::Static(StaticNode::U64(u)) => write_unsigned(output, *u),
+        OwnedValue::Static(StaticNode::F64(f)) => write_float(output, *f),
+        OwnedValue::String(s) => write_binary(output, s),
+        OwnedValue::Array(array) => {
+            write_list_header(output, array.len());
+            for item in array {
+                write_value(output, item);
+            }
+            // Proper lists end with an empty list
+            output.push(NIL_EXT);
+        }
+        OwnedValue::Object(object) => {
+            write_map_header(output, object.len());
+            for (key, value) in &**object {
+                write_atom(output, key);
+                write_value(output, value);
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn write_value(output: &mut Vec<u8>, value: &OwnedValue) {
+    match value {
+        OwnedValue::Null => write_atom(output, "nil"),
+        OwnedValue::Bool(b) => write_atom(output, if *b { "true" } else { "false" }),
+        OwnedValue::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                write_integer(output, i);
+            } else if let Some(u) = n.as_u64() {
+                write_unsigned(output, u);
+            } else {
+                write_float(output, n.as_f64().unwrap_or_default());
+            }
+        }
+        OwnedValue::String(s) => write_binary(output, s),
+        OwnedValue::Array(array) => {
+            write_list_header(output, array.len());
+            for item in array {
+                write_value(output, item);
+            }
+            // Proper lists end with an empty list
+            output.push(NIL_EXT);
+        }
+        OwnedValue::Object(object) => {
+            write_map_header(output, object.len());
+            for (key, value) in object {
+                write_atom(output, key);
+                write_value(output, value);
+            }
+        }
+    }
+}
+
+/// Atoms can't be longer than 255 characters, so longer ones are written as
+/// binaries instead.
+fn write_atom(output: &mut Vec<u8>, atom: &str) {
+    let Ok(len) = u8::try_from(atom.len()) else {
+        write_binary(output, atom);
+        return;
+    };
+
+    output.push(SMALL_ATOM_UTF8_EXT);
+    output.push(len);
+    output.extend_from_slice(atom.as_bytes());
+}
+
+fn write_integer(output: &mut Vec<u8>, int: i64) {
+    if let Ok(small) = u8::try_from(int) {
+        output.push(SMALL_INTEGER_EXT);
+        output.push(small);
+    } else if let Ok(int) = i32::try_from(int) {
+        output.push(INTEGER_EXT);
+        output.extend_from_slice(&int.to_be_bytes());
+    } else {
+        write_big(output, int.is_negative(), int.unsigned_abs());
+    }
+}
+
+fn write_unsigned(output: &mut Vec<u8>, int: u64) {
+    if let Ok(int) = i64::try_from(int) {
+        write_integer(output, int);
+    } else {
+        write_big(output, false, int);
+    }
+}
+
+fn write_big(output: &mut Vec<u8>, negative: bool, magnitude: u64) {
+    let bytes = magnitude.to_le_bytes();
+    let len = bytes.iter().rposition(|b| *b != 0).map_or(0, |pos| pos + 1);
+
+    output.push(SMALL_BIG_EXT);
+    output.push(len as u8);
+    output.push(u8::from(negative));
+    output.extend_from_slice(&bytes[..len]);
+}
+
+fn write_float(output: &mut Vec<u8>, float: f64) {
+    output.push(NEW_FLOAT_EXT);
+    output.extend_from_slice(&float.to_be_bytes());
+}
+
+fn write_binary(output: &mut Vec<u8>, binary: &str) {
+    output.push(BINARY_EXT);
+    output.extend_from_slice(&(binary.len() as u32).to_be_bytes());
+    output.extend_from_slice(binary.as_bytes());
+}
+
+/// Empty lists consist of only the [`NIL_EXT`] tail.
+fn write_list_header(output: &mut Vec<u8>, len: usize) {
+    if len > 0 {
+        output.push(LIST_EXT);
+        output.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+fn write_map_header(output: &mut Vec<u8>, len: usize) {
+    output.push(MAP_EXT);
+    output.extend_from_slice(&(len as u32).to_be_bytes());
+}
+
+/// Reader for the subset of ETF that gateway clients send.
+struct Decoder<'a> {
+    input: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    fn read_bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.input.len() < len {
+            return None;
+        }
+
+        let (bytes, rest) = self.input.split_at(len);
+        self.input = rest;
+
+        Some(bytes)
+    }
+
+    fn read_u8(&mut self) -> Option<u8> {
+        self.read_bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn read_u16(&mut self) -> Option<u16> {
+        Some(u16::from_be_bytes(self.read_bytes(2)?.try_into().ok()?))
+    }
+
+    fn read_u32(&mut self) -> Option<u32> {
+        Some(u32::from_be_bytes(self.read_bytes(4)?.try_into().ok()?))
+    }
+
+    fn read_string(&mut self, len: usize) -> Option<String> {
+        String::from_utf8(self.read_bytes(len)?.to_vec()).ok()
+    }
+
+    fn read_value(&mut self, depth: usize) -> Option<OwnedValue> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+
+        let value = match self.read_u8()? {
+            SMALL_INTEGER_EXT => OwnedValue::from(u64::from(self.read_u8()?)),
+            INTEGER_EXT => OwnedValue::from(i64::from(self.read_u32()? as i32)),
+            NEW_FLOAT_EXT => {
+                OwnedValue::from(f64::from_be_bytes(self.read_bytes(8)?.try_into().ok()?))
+            }
+            SMALL_BIG_EXT => {
+                let len = usize::from(self.read_u8()?);
+                let negative = self.read_u8()? != 0;
+                let bytes = self.read_bytes(len)?;
+
+                if len > 8 {
+                    return None;
+                }
+
+                let mut magnitude = [0; 8];
+                magnitude[..len].copy_from_slice(bytes);
+                let magnitude = u64::from_le_bytes(magnitude);
+
+                if negative {
+                    OwnedValue::from(0_i64.checked_sub_unsigned(magnitude)?)
+                } else {
+                    OwnedValue::from(magnitude)
+                }
+            }
+            ATOM_EXT | ATOM_UTF8_EXT => {
+                let len = usize::from(self.read_u16()?);
+                atom_to_value(self.read_string(len)?)
+            }
+            SMALL_ATOM_EXT | SMALL_ATOM_UTF8_EXT => {
+                let len = usize::from(self.read_u8()?);
+                atom_to_value(self.read_string(len)?)
+            }
+            BINARY_EXT => {
+                let len = self.read_u32()? as usize;
+                OwnedValue::from(self.read_string(len)?)
+            }
+            STRING_EXT => {
+                // Lists of small integers are encoded as strings
+                let len = usize::from(self.read_u16()?);
+                let bytes = self.read_bytes(len)?;
+
+                OwnedValue::from(
+                    bytes
+                        .iter()
+                        .map(|b| OwnedValue::from(u64::from(*b)))
+                        .collect::<Vec<_>>(),
+                )
+            }
+            NIL_EXT => OwnedValue::Array(Vec::new()),
+            LIST_EXT => {
+                let len = self.read_u32()? as usize;
+                let items = self.read_values(len, depth + 1)?;
+
+                // Improper lists are not supported
+                if self.read_u8()? != NIL_EXT {
+                    return None;
+                }
+
+                OwnedValue::from(items)
+            }
+            SMALL_TUPLE_EXT => {
+                let len = usize::from(self.read_u8()?);
+                OwnedValue::from(self.read_values(len, depth + 1)?)
+            }
+            LARGE_TUPLE_EXT => {
+                let len = self.read_u32()? as usize;
+                OwnedValue::from(self.read_values(len, depth + 1)?)
+            }
+            MAP_EXT => {
+                let len = self.read_u32()? as usize;
+                let mut object = JsonObject::with_capacity(len.min(64));
+
+                for _ in 0..len {
+                    let key = match self.read_value(depth + 1)? {
+                        OwnedValue::String(key) => key,
+                        other => other.to_string(),
+                    };
+                    let value = self.read_value(depth + 1)?;
+
+                    object.insert(key, value);
+                }
+
+                object_to_value(object)
+            }
+            _ => return None,
+        };
+
+        Some(value)
+    }
+
+    fn read_values(&mut self, len: usize, depth: usize) -> Option<Vec<OwnedValue>> {
+        let mut values = Vec::with_capacity(len.min(64));
+
+        for _ in 0..len {
+            values.push(self.read_value(depth)?);
+        }
+
+        Some(values)
+    }
+}
+
+fn atom_to_value(atom: String) -> OwnedValue {
+    match atom.as_str() {
+        "nil" => null(),
+        "true" => OwnedValue::from(true),
+        "false" => OwnedValue::from(false),
+        _ => OwnedValue::from(atom),
+    }
+}
+
+#[cfg(feature = "simd-json")]
+const fn null() -> OwnedValue {
+    OwnedValue::Static(StaticNode::Null)
+}
+
+#[cfg(not(feature = "simd-json"))]
+const fn null() -> OwnedValue {
+    OwnedValue::Null
+}
+
+#[cfg(feature = "simd-json")]
+fn object_to_value(object: JsonObject) -> OwnedValue {
+    OwnedValue::from(object)
+}
+
+#[cfg(not(feature = "simd-json"))]
+fn object_to_value(object: JsonObject) -> OwnedValue {
+    OwnedValue::Object(object.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{
+        etf_to_json, json_to_etf, EtfTemplate, BINARY_EXT, FORMAT_VERSION, LIST_EXT, MAP_EXT,
+        MAX_DEPTH, NIL_EXT, SMALL_ATOM_UTF8_EXT, SMALL_INTEGER_EXT,
+    };
+
+    /// Convert JSON to ETF and back.
+    fn roundtrip(json: &Value) -> Value {
+        let etf = json_to_etf(serde_json::to_vec(json).unwrap()).unwrap();
+
+        serde_json::from_slice(&etf_to_json(&etf).unwrap()).unwrap()
+    }
+
+    /// Lists nested `depth` times, with an empty list in the innermost one.
+    fn nested_lists(depth: usize) -> Vec<u8> {
+        let mut etf = vec![FORMAT_VERSION];
+
+        for _ in 0..depth {
+            etf.extend_from_slice(&[LIST_EXT, 0, 0, 0, 1]);
+        }
+
+        // The innermost empty list and the tails of all others
+        etf.extend(std::iter::repeat(NIL_EXT).take(depth + 1));
+
+        etf
+    }
+
+    #[test]
+    fn nested() {
+        let json = json!({
+            "op": 0,
+            "d": {
+                "guilds": [{"id": "1", "unavailable": true}, {"id": "2", "features": []}],
+                "nested": [[1, [2, [3]]], {"a": {"b": null}}],
+                "flag": false
+            }
+        });
+
+        assert_eq!(roundtrip(&json), json);
+    }
+
+    #[test]
+    fn integers() {
+        let json = json!({
+            "small": 255,
+            "negative": -1,
+            "i32": -2_147_483_648_i64,
+            "big": 1_099_511_627_776_i64,
+            "big_negative": -1_099_511_627_776_i64,
+            "i64_min": i64::MIN,
+            "u64_max": u64::MAX,
+            "zero": 0
+        });
+
+        assert_eq!(roundtrip(&json), json);
+    }
+
+    #[test]
+    fn floats() {
+        let json = json!({"a": 1.5, "b": -0.25, "c": 1e300});
+
+        assert_eq!(roundtrip(&json), json);
+    }
+
+    #[test]
+    fn long_keys() {
+        let object = |key: String| Value::Object([(key, json!(1))].into_iter().collect());
+        let json = object("k".repeat(256));
+
+        let etf = json_to_etf(serde_json::to_vec(&json).unwrap()).unwrap();
+
+        // Keys that fit in an atom are atoms, longer ones are binaries
+        assert_eq!(
+            json_to_etf(br#"{"k":1}"#.to_vec()).unwrap(),
+            [
+                FORMAT_VERSION,
+                MAP_EXT,
+                0,
+                0,
+                0,
+                1,
+                SMALL_ATOM_UTF8_EXT,
+                1,
+                b'k',
+                SMALL_INTEGER_EXT,
+                1
+            ]
+        );
+        assert_eq!(etf[6], BINARY_EXT);
+        assert_eq!(&etf[7..11], &256_u32.to_be_bytes());
+
+        assert_eq!(roundtrip(&json), json);
+        assert_eq!(roundtrip(&object("k".repeat(255))), object("k".repeat(255)));
+    }
+
+    #[test]
+    fn max_depth() {
+        assert!(etf_to_json(&nested_lists(MAX_DEPTH)).is_some());
+        assert!(etf_to_json(&nested_lists(MAX_DEPTH + 1)).is_none());
+    }
+
+    #[test]
+    fn template() {
+        let event = json!({"op": 0, "s": 5, "t": "MESSAGE_CREATE", "d": {"s": 1}});
+        let template = EtfTemplate::new(&event.to_string()).unwrap();
+
+        let decode = |etf: Vec<u8>| -> Value {
+            serde_json::from_slice(&etf_to_json(&etf).unwrap()).unwrap()
+        };
+
+        // Only the sequence number of the event itself is replaced
+        for sequence in [1, 300, 70_000] {
+            let mut expected = event.clone();
+            expected["s"] = sequence.into();
+
+            assert_eq!(
+                decode(template.with_sequence(Some(sequence)).unwrap()),
+                expected
+            );
+        }
+
+        // Sequence numbers that don't fit into an INTEGER_EXT
+        assert!(template.with_sequence(Some(1 << 31)).is_none());
+
+        // Events without a sequence number are encoded as they are
+        let hello = json!({"op": 10, "s": null, "d": {"heartbeat_interval": 41250}});
+        let template = EtfTemplate::new(&hello.to_string()).unwrap();
+
+        assert_eq!(decode(template.with_sequence(None).unwrap()), hello);
+    }
+}
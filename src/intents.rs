@@ -0,0 +1,334 @@
+use twilight_model::{
+    gateway::Intents,
+    id::{marker::GuildMarker, Id},
+};
+
+/// Intents a client declared in its IDENTIFY.
+///
+/// Events are relayed for the intents the proxy was configured with, which
+/// may be more than a client asked for, so they are filtered per client.
+#[derive(Clone, Copy)]
+pub struct ClientIntents(Intents);
+
+impl ClientIntents {
+    pub const fn new(intents: Intents) -> Self {
+        Self(intents)
+    }
+
+    /// Whether an event of this type, which belongs to the guild if it has
+    /// an ID, may be sent to the client.
+    pub fn allows(self, event_type: &str, guild_id: Option<Id<GuildMarker>>) -> bool {
+        let required = match direct_message_intents(event_type) {
+            // Events like MESSAGE_CREATE have no guild ID if they happened in a DM
+            Some(intents) if guild_id.is_none() => intents,
+            _ => required_intents(event_type),
+        };
+
+        self.0.contains(required)
+    }
+}
+
+/// Intents that cause Discord to send an event of this type in a guild, as
+/// documented in <https://discord.com/developers/docs/topics/gateway#list-of-intents>
+fn required_intents(event_type: &str) -> Intents {
+    match event_type {
+        "GUILD_CREATE"
+        | "GUILD_UPDATE"
+        | "GUILD_DELETE"
+        | "GUILD_ROLE_CREATE"
+        | "GUILD_ROLE_UPDATE"
+        | "GUILD_ROLE_DELETE"
+        | "CHANNEL_CREATE"
+        | "CHANNEL_UPDATE"
+        | "CHANNEL_DELETE"
+        | "THREAD_CREATE"
+        | "THREAD_UPDATE"
+        | "THREAD_DELETE"
+        | "THREAD_LIST_SYNC"
+        | "THREAD_MEMBER_UPDATE"
+        | "STAGE_INSTANCE_CREATE"
+        | "STAGE_INSTANCE_UPDATE"
+        | "STAGE_INSTANCE_DELETE" => Intents::GUILDS,
+        "GUILD_MEMBER_ADD"
+        | "GUILD_MEMBER_UPDATE"
+        | "GUILD_MEMBER_REMOVE"
+        | "THREAD_MEMBERS_UPDATE" => Intents::GUILD_MEMBERS,
+        "GUILD_AUDIT_LOG_ENTRY_CREATE" | "GUILD_BAN_ADD" | "GUILD_BAN_REMOVE" => {
+            Intents::GUILD_MODERATION
+        }
+        "GUILD_EMOJIS_UPDATE" | "GUILD_STICKERS_UPDATE" => Intents::GUILD_EMOJIS_AND_STICKERS,
+        "GUILD_INTEGRATIONS_UPDATE"
+        | "INTEGRATION_CREATE"
+        | "INTEGRATION_UPDATE"
+        | "INTEGRATION_DELETE" => Intents::GUILD_INTEGRATIONS,
+        "WEBHOOKS_UPDATE" => Intents::GUILD_WEBHOOKS,
+        "INVITE_CREATE" | "INVITE_DELETE" => Intents::GUILD_INVITES,
+        "VOICE_STATE_UPDATE" => Intents::GUILD_VOICE_STATES,
+        "PRESENCE_UPDATE" => Intents::GUILD_PRESENCES,
+        "CHANNEL_PINS_UPDATE" => Intents::GUILDS,
+        "MESSAGE_CREATE" | "MESSAGE_UPDATE" | "MESSAGE_DELETE" | "MESSAGE_DELETE_BULK" => {
+            Intents::GUILD_MESSAGES
+        }
+        "MESSAGE_REACTION_ADD"
+        | "MESSAGE_REACTION_REMOVE"
+        | "MESSAGE_REACTION_REMOVE_ALL"
+        | "MESSAGE_REACTION_REMOVE_EMOJI" => Intents::GUILD_MESSAGE_REACTIONS,
+        "TYPING_START" => Intents::GUILD_MESSAGE_TYPING,
+        "GUILD_SCHEDULED_EVENT_CREATE"
+        | "GUILD_SCHEDULED_EVENT_UPDATE"
+        | "GUILD_SCHEDULED_EVENT_DELETE"
+        | "GUILD_SCHEDULED_EVENT_USER_ADD"
+        | "GUILD_SCHEDULED_EVENT_USER_REMOVE" => Intents::GUILD_SCHEDULED_EVENTS,
+        "AUTO_MODERATION_RULE_CREATE"
+        | "AUTO_MODERATION_RULE_UPDATE"
+        | "AUTO_MODERATION_RULE_DELETE" => Intents::AUTO_MODERATION_CONFIGURATION,
+        "AUTO_MODERATION_ACTION_EXECUTION" => Intents::AUTO_MODERATION_EXECUTION,
+        _ => Intents::empty(),
+    }
+}
+
+/// Intents that cause Discord to send an event of this type in a DM, if it is
+/// sent in DMs at all.
+fn direct_message_intents(event_type: &str) -> Option<Intents> {
+    match event_type {
+        "CHANNEL_PINS_UPDATE" | "MESSAGE_CREATE" | "MESSAGE_UPDATE" | "MESSAGE_DELETE" => {
+            Some(Intents::DIRECT_MESSAGES)
+        }
+        "MESSAGE_REACTION_ADD"
+        | "MESSAGE_REACTION_REMOVE"
+        | "MESSAGE_REACTION_REMOVE_ALL"
+        | "MESSAGE_REACTION_REMOVE_EMOJI" => Some(Intents::DIRECT_MESSAGE_REACTIONS),
+        "TYPING_START" => Some(Intents::DIRECT_MESSAGE_TYPING),
+        _ => None,
+    }
+}
+
+/// Find the ID of the guild an event belongs to without parsing the payload.
+///
+/// This is `d.guild_id` for most events and `d.id` for the events about a
+/// guild itself. Keys of nested objects, like the `guild_id` of a referenced
+/// message, are skipped.
+pub fn find_guild_id(payload: &str, event_type: &str) -> Option<Id<GuildMarker>> {
+    let key: &[u8] = match event_type {
+        "GUILD_CREATE" | "GUILD_UPDATE" | "GUILD_DELETE" => br#""id""#,
+        _ => br#""guild_id""#,
+    };
+
+    let bytes = payload.as_bytes();
+    let mut depth = 0_usize;
+    let mut position = 0;
+
+    while position < bytes.len() {
+        match bytes[position] {
+            b'{' | b'[' => depth += 1,
+            b'}' | b']' => depth = depth.saturating_sub(1),
+            b'"' => {
+                let start = position;
+                position += 1;
+
+                // Skip to the closing quote, including escaped quotes
+                while position < bytes.len() && bytes[position] != b'"' {
+                    if bytes[position] == b'\\' {
+                        position += 1;
+                    }
+
+                    position += 1;
+                }
+
+                // The payload itself is at depth 1, so the keys of d are at depth 2
+                if depth == 2 && bytes.get(start..=position) == Some(key) {
+                    if let Some(guild_id) = payload.get(position + 1..).and_then(parse_id) {
+                        return Some(guild_id);
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        position += 1;
+    }
+
+    None
+}
+
+/// Parse the ID after a key, which is `None` if this was a string value
+/// instead.
+fn parse_id(rest: &str) -> Option<Id<GuildMarker>> {
+    let value = rest
+        .trim_start()
+        .strip_prefix(':')?
+        .trim_start()
+        .strip_prefix('"')?;
+    let end = value.find('"')?;
+
+    value[..end].parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use twilight_model::{gateway::Intents, id::Id};
+
+    use super::{find_guild_id, ClientIntents};
+    use crate::deserializer::{EventTypeInfo, GatewayEvent};
+
+    const GUILD_MESSAGE: &str = r#"{"t":"MESSAGE_CREATE","s":1,"op":0,"d":{"id":"1","channel_id":"2","guild_id":"3","content":""}}"#;
+    const DIRECT_MESSAGE: &str =
+        r#"{"t":"MESSAGE_CREATE","s":1,"op":0,"d":{"id":"1","channel_id":"2","content":""}}"#;
+    const GUILD_REACTION: &str = r#"{"t":"MESSAGE_REACTION_ADD","s":1,"op":0,"d":{"user_id":"1","message_id":"2","guild_id":"3"}}"#;
+    const DIRECT_REACTION: &str =
+        r#"{"t":"MESSAGE_REACTION_ADD","s":1,"op":0,"d":{"user_id":"1","message_id":"2"}}"#;
+    const GUILD_TYPING: &str =
+        r#"{"t":"TYPING_START","s":1,"op":0,"d":{"user_id":"1","guild_id":"3"}}"#;
+    const DIRECT_TYPING: &str = r#"{"t":"TYPING_START","s":1,"op":0,"d":{"user_id":"1"}}"#;
+    const GUILD_EVENT: &str = r#"{"t":"GUILD_MEMBER_ADD","s":1,"op":0,"d":{"guild_id":"3"}}"#;
+    const AUDIT_LOG_ENTRY: &str = r#"{"t":"GUILD_AUDIT_LOG_ENTRY_CREATE","s":1,"op":0,"d":{"id":"1","guild_id":"3","user_id":"4","target_id":"5","action_type":22,"changes":[]}}"#;
+
+    /// Whether a client with these intents gets the payload, with its guild
+    /// ID found like dispatch does.
+    fn allows(intents: Intents, payload: &str, event_type: &str) -> bool {
+        ClientIntents::new(intents).allows(event_type, find_guild_id(payload, event_type))
+    }
+
+    #[test]
+    fn message_events() {
+        let cases = [
+            (GUILD_MESSAGE, Intents::GUILD_MESSAGES, true),
+            (DIRECT_MESSAGE, Intents::GUILD_MESSAGES, false),
+            (GUILD_MESSAGE, Intents::DIRECT_MESSAGES, false),
+            (DIRECT_MESSAGE, Intents::DIRECT_MESSAGES, true),
+            (GUILD_REACTION, Intents::GUILD_MESSAGE_REACTIONS, true),
+            (DIRECT_REACTION, Intents::GUILD_MESSAGE_REACTIONS, false),
+            (GUILD_REACTION, Intents::DIRECT_MESSAGE_REACTIONS, false),
+            (DIRECT_REACTION, Intents::DIRECT_MESSAGE_REACTIONS, true),
+            (GUILD_TYPING, Intents::GUILD_MESSAGE_TYPING, true),
+            (DIRECT_TYPING, Intents::GUILD_MESSAGE_TYPING, false),
+            (GUILD_TYPING, Intents::DIRECT_MESSAGE_TYPING, false),
+            (DIRECT_TYPING, Intents::DIRECT_MESSAGE_TYPING, true),
+        ];
+
+        for (payload, intents, allowed) in cases {
+            let EventTypeInfo(event_type, _) = GatewayEvent::from_json(payload)
+                .and_then(|event| event.into_parts().2)
+                .unwrap();
+
+            assert_eq!(
+                allows(intents, payload, event_type),
+                allowed,
+                "{payload} with {intents:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn privileged_events() {
+        let cases = [
+            ("GUILD_MEMBER_ADD", Intents::GUILD_MEMBERS, true),
+            ("GUILD_MEMBER_UPDATE", Intents::GUILD_MEMBERS, true),
+            ("GUILD_MEMBER_REMOVE", Intents::GUILD_MEMBERS, true),
+            ("GUILD_MEMBER_ADD", Intents::GUILDS, false),
+            (
+                "GUILD_MEMBER_UPDATE",
+                Intents::GUILDS | Intents::GUILD_PRESENCES,
+                false,
+            ),
+            ("PRESENCE_UPDATE", Intents::GUILD_PRESENCES, true),
+            (
+                "PRESENCE_UPDATE",
+                Intents::GUILDS | Intents::GUILD_MEMBERS,
+                false,
+            ),
+            // MESSAGE_CONTENT only changes what message events contain
+            ("MESSAGE_CREATE", Intents::MESSAGE_CONTENT, false),
+            (
+                "MESSAGE_CREATE",
+                Intents::GUILD_MESSAGES | Intents::MESSAGE_CONTENT,
+                true,
+            ),
+        ];
+
+        for (event_type, intents, allowed) in cases {
+            assert_eq!(
+                allows(intents, GUILD_EVENT, event_type),
+                allowed,
+                "{event_type} with {intents:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn audit_log_entries() {
+        let cases = [
+            (Intents::GUILD_MODERATION, true),
+            (Intents::GUILDS | Intents::GUILD_MODERATION, true),
+            (Intents::GUILDS, false),
+            (Intents::GUILD_MEMBERS | Intents::GUILD_MESSAGES, false),
+        ];
+
+        for (intents, allowed) in cases {
+            assert_eq!(
+                allows(intents, AUDIT_LOG_ENTRY, "GUILD_AUDIT_LOG_ENTRY_CREATE"),
+                allowed,
+                "{intents:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_events() {
+        // Events the proxy does not know are sent to every client
+        for intents in [Intents::empty(), Intents::GUILDS, Intents::all()] {
+            assert!(allows(intents, GUILD_EVENT, "SOME_NEW_EVENT"));
+            assert!(allows(intents, DIRECT_MESSAGE, "SOME_NEW_EVENT"));
+        }
+    }
+
+    #[test]
+    fn guild_ids() {
+        let cases = [
+            // Quotes and brackets in strings are not keys or objects
+            (
+                r#"{"t":"MESSAGE_CREATE","s":1,"op":0,"d":{"content":"\"guild_id\":\"9\"}{","guild_id":"3"}}"#,
+                Some(3),
+            ),
+            (
+                r#"{"t":"MESSAGE_CREATE","s":1,"op":0,"d":{"content":"\\","guild_id":"3"}}"#,
+                Some(3),
+            ),
+            // Keys of nested objects belong to something else
+            (
+                r#"{"t":"MESSAGE_CREATE","s":1,"op":0,"d":{"message_reference":{"guild_id":"9"},"guild_id":"3"}}"#,
+                Some(3),
+            ),
+            (
+                r#"{"t":"MESSAGE_CREATE","s":1,"op":0,"d":{"message_reference":{"guild_id":"9"}}}"#,
+                None,
+            ),
+            // Events about a guild itself have its ID as the ID
+            (
+                r#"{"t":"GUILD_CREATE","s":1,"op":0,"d":{"roles":[{"id":"9"}],"channels":[{"id":"9","guild_id":"9"}],"id":"3"}}"#,
+                Some(3),
+            ),
+            (
+                r#"{"t":"GUILD_DELETE","s":1,"op":0,"d":{"id":"3","unavailable":true}}"#,
+                Some(3),
+            ),
+            (DIRECT_MESSAGE, None),
+            (
+                r#"{"t":"MESSAGE_CREATE","s":1,"op":0,"d":{"guild_id":null}}"#,
+                None,
+            ),
+        ];
+
+        for (payload, guild_id) in cases {
+            let EventTypeInfo(event_type, _) = GatewayEvent::from_json(payload)
+                .and_then(|event| event.into_parts().2)
+                .unwrap();
+
+            assert_eq!(
+                find_guild_id(payload, event_type),
+                guild_id.map(Id::new),
+                "{payload}"
+            );
+        }
+    }
+}
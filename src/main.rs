@@ -8,13 +8,20 @@
     clippy::struct_excessive_bools,
     clippy::option_if_let_else, // I disagree with this lint
 )]
-use libc::{c_int, sighandler_t, signal, SIGINT, SIGTERM};
-use metrics_exporter_prometheus::PrometheusBuilder;
 use mimalloc::MiMalloc;
-use tokio::sync::broadcast;
-use tracing::{debug, error, info};
-use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt, util::SubscriberInitExt};
-use twilight_cache_inmemory::InMemoryCache;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, watch, Notify},
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::{
+    filter::{filter_fn, LevelFilter},
+    layer::SubscriberExt,
+    util::SubscriberInitExt,
+    Layer,
+};
 use twilight_gateway::{Config, ConfigBuilder, Shard, ShardId};
 use twilight_gateway_queue::{LargeBotQueue, Queue};
 use twilight_http::Client;
@@ -24,45 +31,79 @@ use std::{
     collections::HashMap,
     error::Error,
     str::FromStr,
-    sync::{Arc, RwLock},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize},
+        Arc, RwLock,
+    },
+    time::Duration,
 };
 
-use crate::config::CONFIG;
+use crate::config::{LogFormat, CONFIG};
 
+mod audit;
 mod cache;
 mod config;
 mod deserializer;
 mod dispatch;
+mod encoding;
+mod intents;
+mod metrics;
 mod model;
+#[cfg(feature = "opentelemetry")]
+mod otel;
+mod ratelimit;
+mod reconnect;
 mod server;
 mod state;
+mod subscription;
+#[cfg(feature = "tls")]
+mod tls;
 mod upgrade;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-pub extern "C" fn handler(_: c_int) {
-    std::process::exit(0);
-}
+/// Cancel the token once SIGINT or SIGTERM is received.
+async fn shutdown_on_signal(shutdown: CancellationToken) {
+    let mut sigterm = signal(SignalKind::terminate()).unwrap();
+    let mut sigint = signal(SignalKind::interrupt()).unwrap();
 
-unsafe fn set_os_handlers() {
-    signal(SIGINT, handler as extern "C" fn(_) as sighandler_t);
-    signal(SIGTERM, handler as extern "C" fn(_) as sighandler_t);
+    tokio::select! {
+        _ = sigterm.recv() => {},
+        _ = sigint.recv() => {},
+    };
+
+    info!("Shutting down, no longer accepting connections");
+    shutdown.cancel();
 }
 
 #[allow(clippy::cognitive_complexity)]
 async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
     let level_filter = LevelFilter::from_str(&CONFIG.log_level).unwrap_or(LevelFilter::INFO);
     let fmt_layer = tracing_subscriber::fmt::layer();
-    tracing_subscriber::registry()
-        .with(fmt_layer)
-        .with(level_filter)
-        .init();
+    let fmt_layer = match CONFIG.log_format {
+        LogFormat::Full => fmt_layer.with_ansi(false).boxed(),
+        LogFormat::Compact => fmt_layer.compact().with_ansi(false).boxed(),
+        LogFormat::Pretty => fmt_layer.pretty().boxed(),
+        LogFormat::Json => fmt_layer.json().boxed(),
+    };
+    // Audit events only go to the audit log if there is one
+    let fmt_filter =
+        filter_fn(|metadata| CONFIG.audit_log.is_none() || metadata.target() != audit::TARGET);
+    let audit_layer = CONFIG.audit_log.as_deref().map(audit::layer).transpose()?;
+    let registry = tracing_subscriber::registry()
+        .with(fmt_layer.with_filter(level_filter).with_filter(fmt_filter))
+        .with(audit_layer);
+    #[cfg(feature = "opentelemetry")]
+    let registry = registry.with(otel::layer()?);
+    registry.init();
 
     // Set up metrics collection
-    let recorder = PrometheusBuilder::new().build_recorder();
-    let metrics_handle = Arc::new(recorder.handle());
-    metrics::set_boxed_recorder(Box::new(recorder)).unwrap();
+    let metrics_handle = metrics::install();
+
+    if let Some(metrics_port) = CONFIG.metrics_port {
+        tokio::spawn(metrics::run(metrics_port, metrics_handle.clone()));
+    }
 
     // Set up a HTTPClient
     let mut client_builder = Client::builder().token(CONFIG.token.clone());
@@ -94,8 +135,28 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut shards = Vec::with_capacity((shard_end - shard_start) as usize);
 
     info!("Creating shards {shard_start} to {shard_end_inclusive} of {shard_count} total",);
+    info!(
+        "Buffering up to {} events per shard for clients",
+        CONFIG.backpressure
+    );
+
+    // Testing against a mock gateway is easier without TLS, so allow ws:// as well
+    let gateway_url = if let Some(gateway_url) = CONFIG.gateway_url.clone() {
+        if !gateway_url.starts_with("wss://") && !gateway_url.starts_with("ws://") {
+            return Err(format!("Gateway URL {gateway_url} is not a WebSocket URL").into());
+        }
+
+        info!(
+            "Connecting to the gateway at {gateway_url} instead of {}",
+            gateway.url
+        );
+        gateway_url
+    } else {
+        gateway.url
+    };
 
     let config = Config::builder(CONFIG.token.clone(), CONFIG.intents)
+        .gateway_url(Some(gateway_url))
         .queue(queue)
         .event_types(CONFIG.cache.clone().into())
         .build();
@@ -118,13 +179,12 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
         // we need to make a broadcast channel with the events
         let (broadcast_tx, _) = broadcast::channel(CONFIG.backpressure);
 
-        let cache = Arc::new(
-            InMemoryCache::builder()
-                .resource_types(CONFIG.cache.clone().into())
-                .message_cache_size(0)
-                .build(),
+        let guild_cache = cache::Guilds::new(
+            CONFIG.cache.clone().into(),
+            CONFIG.intents,
+            shard_id,
+            shard_count,
         );
-        let guild_cache = cache::Guilds::new(cache.clone(), shard_id);
 
         let ready = state::Ready::new();
 
@@ -134,17 +194,19 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
             events: broadcast_tx.clone(),
             ready,
             guilds: guild_cache,
+            info: RwLock::new(state::ShardInfo::new(shard_id)),
+            replay: state::ReplayBuffer::new(CONFIG.replay_buffer),
+            heartbeat_interval: AtomicU64::new(0),
+            closed: watch::channel(None).0,
         });
 
         // Now pipe the events into the broadcast
         // and handle state updates for the guild cache
         // and set the ready event if received
-        tokio::spawn(dispatch::events(
-            shard,
-            shard_status.clone(),
-            shard_id,
-            broadcast_tx,
-        ));
+        tokio::spawn(
+            dispatch::events(shard, shard_status.clone(), shard_id, broadcast_tx)
+                .instrument(info_span!("shard", shard_id)),
+        );
 
         shards.push(shard_status);
 
@@ -155,18 +217,40 @@ async fn run() -> Result<(), Box<dyn Error + Send + Sync>> {
         shards,
         shard_count,
         sessions: RwLock::new(HashMap::new()),
+        clients: AtomicUsize::new(0),
+        client_disconnected: Notify::new(),
+        shutdown: CancellationToken::new(),
+        connection_limiter: ratelimit::ConnectionLimiter::new(&CONFIG.connection_rate_limit),
     });
 
-    if let Err(e) = server::run(CONFIG.port, state, metrics_handle).await {
+    #[cfg(not(feature = "tls"))]
+    if CONFIG.tls_cert_path.is_some() || CONFIG.tls_key_path.is_some() {
+        warn!("A TLS certificate is configured, but the proxy was built without TLS support");
+    }
+
+    tokio::spawn(shutdown_on_signal(state.shutdown.clone()));
+
+    if let Err(e) = server::run(CONFIG.port, state.clone(), metrics_handle).await {
         error!("{}", e);
     };
 
+    // The server only stops when shutting down, clients were told to disconnect by now
+    let drain_timeout = Duration::from_secs(CONFIG.shutdown_timeout);
+
+    if timeout(drain_timeout, state.wait_for_clients())
+        .await
+        .is_err()
+    {
+        warn!("Clients did not disconnect in time, exiting anyways");
+    }
+
+    #[cfg(feature = "opentelemetry")]
+    otel::shutdown();
+
     Ok(())
 }
 
 fn main() {
-    unsafe { set_os_handlers() };
-
     if let Err(e) = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
@@ -0,0 +1,125 @@
+//! Prometheus metrics exported by the proxy.
+//!
+//! All of these are rendered at `/metrics` on the main port, and on the
+//! dedicated `metrics_port` (9090 by default) unless it is set to `null`.
+//!
+//! Shards, labelled by `shard`:
+//! - `gateway_shard_events`: counter of events received, also labelled by
+//!   `event_type`
+//! - `gateway_shard_latency`: gauge of the most recent heartbeat latency in
+//!   seconds
+//! - `gateway_shard_latency_histogram`: histogram of the same latencies
+//! - `gateway_shard_status`: histogram of the connection status, where 0 is
+//!   fatally closed, 1 disconnected, 2 identifying, 3 resuming and 4 connected
+//! - `gateway_sequence_gaps_total`: counter of gaps in the sequence numbers
+//!   of received events, which means events were missed
+//! - `gateway_shard_circuit`: gauge of the state of the circuit breaker, where
+//!   0 is closed, 1 half-open and 2 open
+//!
+//! Cache, labelled by `shard`:
+//! - `gateway_cache_channels`, `gateway_cache_emojis`, `gateway_cache_guilds`,
+//!   `gateway_cache_members`, `gateway_cache_presences`, `gateway_cache_roles`,
+//!   `gateway_cache_scheduled_events`, `gateway_cache_threads`,
+//!   `gateway_cache_unavailable_guilds`, `gateway_cache_users` and
+//!   `gateway_cache_voice_states`: gauges of the amount of cached items,
+//!   updated every 10 seconds
+//! - `gateway_cache_guilds_added`, `gateway_cache_guilds_removed`,
+//!   `gateway_cache_members_added` and `gateway_cache_channels_added`:
+//!   counters of changes caused by events
+//! - `gateway_cache_evictions`: counter of guilds evicted from the cache for
+//!   exceeding a limit, also labelled by the `resource` whose limit it was
+//! - `gateway_cache_emoji_updates` and `gateway_cache_sticker_updates`:
+//!   counters of GUILD_EMOJIS_UPDATEs and GUILD_STICKERS_UPDATEs, which
+//!   replace the cached emojis or stickers of a guild
+//!
+//! Clients, labelled by `encoding` and `zlib`:
+//! - `gateway_client_time_to_first_event_ms`: histogram of the time from the
+//!   WebSocket upgrade until the first event was relayed
+//!
+//! Clients, labelled by `shard` and `event_type`:
+//! - `gateway_proxy_events_sent`: counter of dispatch events sent to clients,
+//!   counted once for every client an event is sent to
+//!
+//! Clients, labelled by `shard`:
+//! - `gateway_proxy_bytes_sent_total`: counter of bytes of events sent to
+//!   clients, before compression
+//! - `gateway_proxy_bytes_received_total`: counter of bytes received from
+//!   clients after they identified
+//! - `gateway_proxy_fanout_latency_seconds`: histogram of the time in seconds
+//!   from receiving an event from Discord until it was queued for a client,
+//!   recorded once for every client, so its maximum is the time until the
+//!   last client got the event
+//!
+//! Clients, labelled by `shard` and `action`, which is either `disconnect` or
+//! `catch_up`:
+//! - `gateway_clients_lagged`: counter of clients that fell behind by more
+//!   than `backpressure` events
+//!
+//! Clients, labelled by `reason`, which is one of `closed`, `timeout` for
+//! unanswered pings, `lagged`, `rate_limited`, `shard_closed` for shards
+//! Discord closed for good, `invalid` for rejected payloads, `error` and
+//! `shutdown`:
+//! - `gateway_proxy_client_session_duration_seconds`: histogram of how long
+//!   connections lasted in seconds
+//!
+//! Clients, unlabelled:
+//! - `gateway_clients_connected`: gauge of the amount of connected clients
+//! - `gateway_invalid_utf8_total`: counter of payloads from clients that were
+//!   ignored because they are not valid UTF-8
+//! - `gateway_clients_rate_limited`: counter of clients disconnected for being
+//!   rate limited for longer than the grace period
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use tracing::{error, info};
+
+use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+
+/// Install the Prometheus recorder and return a handle for rendering it.
+pub fn install() -> Arc<PrometheusHandle> {
+    let recorder = PrometheusBuilder::new().build_recorder();
+    let handle = Arc::new(recorder.handle());
+    metrics::set_boxed_recorder(Box::new(recorder)).unwrap();
+
+    handle
+}
+
+pub fn render(handle: &PrometheusHandle) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .body(Body::from(handle.render()))
+        .unwrap()
+}
+
+/// Serve only the metrics on a dedicated port.
+pub async fn run(port: u16, handle: Arc<PrometheusHandle>) {
+    let addr: SocketAddr = ([0, 0, 0, 0], port).into();
+
+    let service = make_service_fn(move |_| {
+        let handle = handle.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                let response = match (request.method(), request.uri().path()) {
+                    (&Method::GET, "/metrics") => render(&handle),
+                    _ => Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .body(Body::empty())
+                        .unwrap(),
+                };
+
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+
+    let server = Server::bind(&addr).serve(service);
+
+    info!("Serving metrics on {addr}");
+
+    if let Err(why) = server.await {
+        error!("Fatal metrics server error: {why}");
+    }
+}
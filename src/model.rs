@@ -1,8 +1,16 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 #[cfg(not(feature = "simd-json"))]
 use serde_json::Value as OwnedValue;
 #[cfg(feature = "simd-json")]
 use simd_json::OwnedValue;
+use twilight_model::{
+    gateway::Intents,
+    guild::scheduled_event::GuildScheduledEvent,
+    id::{
+        marker::{ChannelMarker, GuildMarker},
+        Id,
+    },
+};
 
 #[derive(Deserialize)]
 pub struct Identify {
@@ -18,6 +26,8 @@ pub struct Resume {
 pub struct IdentifyInfo {
     #[serde(default)]
     pub compress: Option<bool>,
+    #[serde(default)]
+    pub intents: Option<Intents>,
     pub shard: [u32; 2],
     pub token: String,
 }
@@ -29,9 +39,79 @@ pub struct ResumeInfo {
     pub token: String,
 }
 
+/// The proxy's own payload for subscribing to the events of some guilds.
+#[derive(Deserialize)]
+pub struct SubscribeGuilds {
+    pub d: SubscribeGuildsInfo,
+}
+
+#[derive(Deserialize)]
+pub struct SubscribeGuildsInfo {
+    /// Guilds to receive events of, or all guilds if this is null.
+    #[serde(default)]
+    pub guild_ids: Option<Vec<Id<GuildMarker>>>,
+}
+
+/// The proxy's own payload for getting a fresh GUILD_CREATE of a guild.
+#[derive(Deserialize)]
+pub struct RequestGuildSync {
+    pub d: RequestGuildSyncInfo,
+}
+
+#[derive(Deserialize)]
+pub struct RequestGuildSyncInfo {
+    pub guild_id: Id<GuildMarker>,
+}
+
+#[derive(Deserialize)]
+pub struct Hello {
+    pub d: HelloInfo,
+}
+
+#[derive(Deserialize)]
+pub struct HelloInfo {
+    pub heartbeat_interval: u64,
+}
+
+/// Any dispatch event, for modifying its data before relaying it.
+#[derive(Deserialize, Serialize)]
+pub struct Dispatch {
+    pub t: String,
+    pub s: u64,
+    pub op: u8,
+    pub d: JsonObject,
+}
+
 #[derive(Deserialize)]
 pub struct Ready {
     pub d: JsonObject,
 }
 
+/// Fields of GUILD_CREATE that twilight's cache does not keep track of.
+#[derive(Deserialize)]
+pub struct GuildCreateExtras {
+    pub d: GuildExtras,
+}
+
+#[derive(Deserialize)]
+pub struct GuildExtras {
+    pub id: Id<GuildMarker>,
+    #[serde(default)]
+    pub guild_scheduled_events: Vec<GuildScheduledEvent>,
+}
+
+#[derive(Deserialize)]
+pub struct VoiceChannelStatusUpdate {
+    pub d: VoiceChannelStatus,
+}
+
+/// Status of a voice channel, which twilight does not model yet.
+#[derive(Deserialize, Clone)]
+pub struct VoiceChannelStatus {
+    pub id: Id<ChannelMarker>,
+    pub guild_id: Id<GuildMarker>,
+    #[serde(default)]
+    pub status: Option<String>,
+}
+
 pub type JsonObject = halfbrown::HashMap<String, OwnedValue>;
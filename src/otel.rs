@@ -0,0 +1,37 @@
+use opentelemetry::trace::TraceError;
+use opentelemetry_otlp::WithExportConfig;
+use tracing::Subscriber;
+use tracing_subscriber::{filter::filter_fn, registry::LookupSpan, Layer};
+
+use std::env::var;
+
+/// Build a layer that exports the spans of the proxy via OTLP.
+///
+/// The collector is set with `OTEL_EXPORTER_OTLP_ENDPOINT` and defaults to
+/// `http://localhost:4317`. Only spans are exported, regardless of the log
+/// level, so that the per-event spans can be traced without logging every
+/// event.
+pub fn layer<S>() -> Result<impl Layer<S>, TraceError>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let exporter = opentelemetry_otlp::new_exporter().tonic().with_endpoint(
+        var("OTEL_EXPORTER_OTLP_ENDPOINT").unwrap_or_else(|_| "http://localhost:4317".into()),
+    );
+
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(exporter)
+        .install_batch(opentelemetry::runtime::Tokio)?;
+
+    Ok(tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(filter_fn(|metadata| {
+            metadata.is_span() && metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+        })))
+}
+
+/// Export the spans that are still buffered.
+pub fn shutdown() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
@@ -0,0 +1,271 @@
+use tokio::time::Instant;
+
+use std::{collections::HashMap, net::IpAddr, sync::Mutex, time::Duration};
+
+use crate::config::ConnectionRateLimit;
+
+/// Token bucket that allows a number of actions per period, with bursts of up
+/// to that number.
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    /// Tokens added per second.
+    refill_rate: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(capacity: u32, period: Duration) -> Self {
+        let capacity = f64::from(capacity);
+
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_rate: capacity / period.as_secs_f64(),
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens = elapsed
+            .mul_add(self.refill_rate, self.tokens)
+            .min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Take a token if one is available.
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take a token, returning how long to wait until it is actually available
+    /// if the bucket is empty.
+    pub fn acquire(&mut self) -> Option<Duration> {
+        self.refill();
+        self.tokens -= 1.0;
+
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.refill_rate))
+    }
+}
+
+/// Connection attempts of a single IP.
+struct IpLimit {
+    bucket: TokenBucket,
+    banned_until: Option<Instant>,
+    last_attempt: Instant,
+}
+
+/// Limits how many connections each IP may open per period.
+///
+/// IPs that exceed the limit are rejected until the cooldown passed. Loopback
+/// addresses are never limited, since that is where local bots and reverse
+/// proxies connect from.
+///
+/// The IPs are kept behind a single mutex rather than in a concurrent map,
+/// since it is only locked briefly once per upgrade request and sweeping the
+/// IPs that are not limited anymore needs all of them anyways.
+pub struct ConnectionLimiter {
+    connections: u32,
+    period: Duration,
+    cooldown: Duration,
+    ips: Mutex<HashMap<IpAddr, IpLimit>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: &ConnectionRateLimit) -> Self {
+        Self {
+            connections: config.connections,
+            period: Duration::from_secs(config.period),
+            cooldown: Duration::from_secs(config.cooldown),
+            ips: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record a connection attempt, returning how long the IP has to wait
+    /// if it is rate limited.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        if self.connections == 0 {
+            return Ok(());
+        }
+
+        // IPv4 clients connecting to a dual-stack socket show up as IPv4-mapped
+        let ip = match ip {
+            IpAddr::V6(ip) => ip.to_ipv4_mapped().map_or(IpAddr::V6(ip), IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+
+        if ip.is_loopback() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut ips = self.ips.lock().unwrap();
+
+        if !ips.contains_key(&ip) {
+            // Forget about IPs that would not be limited anymore anyways
+            ips.retain(|_, limit| {
+                now.duration_since(limit.last_attempt) < self.period + self.cooldown
+            });
+        }
+
+        let limit = ips.entry(ip).or_insert_with(|| IpLimit {
+            bucket: TokenBucket::new(self.connections, self.period),
+            banned_until: None,
+            last_attempt: now,
+        });
+        limit.last_attempt = now;
+
+        if let Some(banned_until) = limit.banned_until {
+            if banned_until > now {
+                return Err(banned_until - now);
+            }
+
+            limit.banned_until = None;
+        }
+
+        if limit.bucket.try_acquire() {
+            Ok(())
+        } else {
+            limit.banned_until = Some(now + self.cooldown);
+            Err(self.cooldown)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::advance;
+
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        time::Duration,
+    };
+
+    use super::{ConnectionLimiter, TokenBucket};
+    use crate::config::ConnectionRateLimit;
+
+    /// A limiter of 2 connections per 10 seconds with a cooldown of a minute.
+    fn limiter() -> ConnectionLimiter {
+        ConnectionLimiter::new(&ConnectionRateLimit {
+            connections: 2,
+            period: 10,
+            cooldown: 60,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst() {
+        let mut bucket = TokenBucket::new(3, Duration::from_secs(1));
+
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill() {
+        let mut bucket = TokenBucket::new(2, Duration::from_secs(1));
+
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+
+        advance(Duration::from_millis(500)).await;
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+
+        // Never more than the capacity, no matter how long the bucket was idle
+        advance(Duration::from_secs(60)).await;
+        assert!(bucket.try_acquire());
+        assert!(bucket.try_acquire());
+        assert!(!bucket.try_acquire());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire() {
+        let mut bucket = TokenBucket::new(4, Duration::from_secs(1));
+
+        for _ in 0..4 {
+            assert_eq!(bucket.acquire(), None);
+        }
+
+        // Tokens are taken in advance, so each wait is longer than the last
+        assert_eq!(bucket.acquire(), Some(Duration::from_millis(250)));
+        assert_eq!(bucket.acquire(), Some(Duration::from_millis(500)));
+
+        advance(Duration::from_millis(500)).await;
+        assert_eq!(bucket.acquire(), Some(Duration::from_millis(250)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connection_cooldown() {
+        let limiter = limiter();
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        assert_eq!(limiter.check(ip), Ok(()));
+        assert_eq!(limiter.check(ip), Ok(()));
+        assert_eq!(limiter.check(ip), Err(Duration::from_secs(60)));
+
+        // Other IPs have their own limit
+        assert_eq!(
+            limiter.check(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2))),
+            Ok(())
+        );
+
+        // Attempts during the cooldown don't extend it
+        advance(Duration::from_secs(45)).await;
+        assert_eq!(limiter.check(ip), Err(Duration::from_secs(15)));
+
+        advance(Duration::from_secs(15)).await;
+        assert_eq!(limiter.check(ip), Ok(()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ipv4_mapped() {
+        let limiter = limiter();
+        let ip = Ipv4Addr::new(192, 0, 2, 1);
+
+        assert_eq!(limiter.check(IpAddr::V4(ip)), Ok(()));
+        assert_eq!(limiter.check(IpAddr::V6(ip.to_ipv6_mapped())), Ok(()));
+        assert!(limiter.check(IpAddr::V4(ip)).is_err());
+        assert!(limiter.check(IpAddr::V6(ip.to_ipv6_mapped())).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn loopback() {
+        let limiter = limiter();
+
+        for _ in 0..10 {
+            assert_eq!(limiter.check(IpAddr::V4(Ipv4Addr::LOCALHOST)), Ok(()));
+            assert_eq!(limiter.check(IpAddr::V6(Ipv6Addr::LOCALHOST)), Ok(()));
+            assert_eq!(
+                limiter.check(IpAddr::V6(Ipv4Addr::LOCALHOST.to_ipv6_mapped())),
+                Ok(())
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn connections_disabled() {
+        let limiter = ConnectionLimiter::new(&ConnectionRateLimit {
+            connections: 0,
+            period: 10,
+            cooldown: 60,
+        });
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+
+        for _ in 0..10 {
+            assert_eq!(limiter.check(ip), Ok(()));
+        }
+    }
+}
@@ -0,0 +1,214 @@
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use tokio::time::Instant;
+
+use std::time::Duration;
+
+use crate::config::CircuitBreaker;
+
+/// Delay before the first reconnect attempt.
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Upper bound for the delay between reconnect attempts.
+const MAX_DELAY: Duration = Duration::from_secs(128);
+
+/// Connections that lasted this long count as successful and reset the
+/// backoff.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// State of the circuit breaker of a shard.
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// The shard is connected or reconnecting as usual.
+    Closed,
+    /// The shard is trying to reconnect once after the cooldown.
+    HalfOpen,
+    /// Reconnecting failed too often, the shard waits for the cooldown.
+    Open,
+}
+
+impl CircuitState {
+    pub const fn as_f64(self) -> f64 {
+        match self {
+            Self::Closed => 0.0,
+            Self::HalfOpen => 1.0,
+            Self::Open => 2.0,
+        }
+    }
+}
+
+/// Exponential backoff with jitter for reconnecting a shard to Discord.
+///
+/// Starts at one second and doubles with every failed attempt up to
+/// [`MAX_DELAY`], randomized by ±25% so that shards don't reconnect all at
+/// once after an outage.
+///
+/// After too many consecutive failures, the circuit opens and the shard waits
+/// for the cooldown before trying once more.
+pub struct Backoff {
+    attempts: u32,
+    failures: u32,
+    circuit: CircuitState,
+    connected_at: Option<Instant>,
+    /// Consecutive failures after which the circuit opens, 0 if it never
+    /// does.
+    max_failures: u32,
+    cooldown: Duration,
+}
+
+impl Backoff {
+    pub const fn new(config: &CircuitBreaker) -> Self {
+        Self {
+            attempts: 0,
+            failures: 0,
+            circuit: CircuitState::Closed,
+            connected_at: None,
+            max_failures: config.failures,
+            cooldown: Duration::from_secs(config.cooldown),
+        }
+    }
+
+    pub const fn circuit(&self) -> CircuitState {
+        self.circuit
+    }
+
+    /// Mark the shard as connected, which closes the circuit and resets the
+    /// backoff if it stays connected long enough.
+    pub fn connected(&mut self) {
+        self.connected_at = Some(Instant::now());
+        self.failures = 0;
+        self.circuit = CircuitState::Closed;
+    }
+
+    /// Mark the delay as over, right before the shard reconnects.
+    pub fn reconnecting(&mut self) {
+        if self.circuit == CircuitState::Open {
+            self.circuit = CircuitState::HalfOpen;
+        }
+    }
+
+    /// Delay before the next reconnect attempt after the connection failed.
+    pub fn next_delay(&mut self) -> Duration {
+        if self
+            .connected_at
+            .take()
+            .map_or(false, |connected_at| connected_at.elapsed() >= STABLE_AFTER)
+        {
+            self.attempts = 0;
+        }
+
+        self.failures = self.failures.saturating_add(1);
+
+        if self.max_failures > 0
+            && (self.circuit == CircuitState::HalfOpen || self.failures >= self.max_failures)
+        {
+            self.circuit = CircuitState::Open;
+
+            return self.cooldown;
+        }
+
+        let delay = INITIAL_DELAY
+            .saturating_mul(2_u32.saturating_pow(self.attempts))
+            .min(MAX_DELAY);
+        self.attempts = self.attempts.saturating_add(1);
+
+        delay.mul_f64(thread_rng().gen_range(0.75..=1.25))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::advance;
+
+    use std::time::Duration;
+
+    use super::{Backoff, CircuitState};
+    use crate::config::CircuitBreaker;
+
+    /// A backoff that never opens the circuit.
+    fn backoff() -> Backoff {
+        Backoff::new(&CircuitBreaker {
+            failures: 0,
+            cooldown: 60,
+        })
+    }
+
+    /// Whether the delay is within the jitter of the expected one.
+    fn jittered(delay: Duration, secs: u64) -> bool {
+        let expected = Duration::from_secs(secs);
+
+        delay >= expected.mul_f64(0.75) && delay <= expected.mul_f64(1.25)
+    }
+
+    #[test]
+    fn doubling() {
+        let mut backoff = backoff();
+
+        for secs in [1, 2, 4, 8, 16, 32, 64, 128, 128, 128] {
+            let delay = backoff.next_delay();
+            assert!(jittered(delay, secs), "{delay:?} is not around {secs}s");
+        }
+    }
+
+    #[test]
+    fn jitter() {
+        let delays: Vec<Duration> = (0..100).map(|_| backoff().next_delay()).collect();
+
+        assert!(delays.iter().all(|delay| jittered(*delay, 1)));
+        // Shards that failed at the same time don't reconnect at the same time
+        assert!(delays.iter().any(|delay| *delay != delays[0]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reset() {
+        let mut backoff = backoff();
+
+        for _ in 0..4 {
+            backoff.next_delay();
+        }
+
+        // Connections that drop too quickly keep backing off
+        backoff.connected();
+        advance(Duration::from_secs(59)).await;
+        assert!(jittered(backoff.next_delay(), 16));
+
+        backoff.connected();
+        advance(Duration::from_secs(60)).await;
+        assert!(jittered(backoff.next_delay(), 1));
+        assert!(jittered(backoff.next_delay(), 2));
+    }
+
+    #[test]
+    fn circuit() {
+        let mut backoff = Backoff::new(&CircuitBreaker {
+            failures: 3,
+            cooldown: 60,
+        });
+
+        for _ in 0..2 {
+            assert!(backoff.next_delay() < Duration::from_secs(60));
+            backoff.reconnecting();
+            assert!(backoff.circuit() == CircuitState::Closed);
+        }
+
+        // Opens after too many failures and waits for the cooldown
+        assert_eq!(backoff.next_delay(), Duration::from_secs(60));
+        assert!(backoff.circuit() == CircuitState::Open);
+
+        backoff.reconnecting();
+        assert!(backoff.circuit() == CircuitState::HalfOpen);
+
+        // A single failure while half-open opens it again
+        assert_eq!(backoff.next_delay(), Duration::from_secs(60));
+        assert!(backoff.circuit() == CircuitState::Open);
+
+        backoff.reconnecting();
+        backoff.connected();
+        assert!(backoff.circuit() == CircuitState::Closed);
+
+        // Failures are counted from the last connection
+        assert!(backoff.next_delay() < Duration::from_secs(60));
+        assert!(backoff.circuit() == CircuitState::Closed);
+    }
+}
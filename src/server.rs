@@ -1,48 +1,85 @@
 use flate2::{Compress, Compression, FlushCompress, Status};
 use futures_util::{Sink, SinkExt, StreamExt};
 use hyper::{
+    header::CONTENT_TYPE,
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
     Body, Method, Request, Response, Server, StatusCode,
 };
 use itoa::Buffer;
 use metrics_exporter_prometheus::PrometheusHandle;
+use serde::Serialize;
 #[cfg(not(feature = "simd-json"))]
-use serde_json::{to_string, Value as OwnedValue};
+use serde_json::to_string;
 #[cfg(feature = "simd-json")]
-use simd_json::{to_string, OwnedValue};
+use simd_json::to_string;
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     sync::{
         broadcast::error::RecvError,
-        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        oneshot,
+        mpsc::{channel, unbounded_channel, Receiver, Sender, UnboundedReceiver},
+        oneshot, watch,
     },
+    task::JoinHandle,
+    time::{interval_at, sleep, timeout, Instant},
 };
 use tokio_tungstenite::{
-    tungstenite::{protocol::Role, Error, Message},
+    tungstenite::{
+        protocol::{frame::coding::CloseCode, CloseFrame, Role, WebSocketConfig},
+        Error, Message,
+    },
     WebSocketStream,
 };
-use tracing::{debug, error, info, trace, warn};
+use tracing::{debug, error, field, info, trace, trace_span, warn, Instrument, Span};
+use twilight_model::{
+    gateway::Intents,
+    id::{marker::GuildMarker, Id},
+};
 
-use std::{convert::Infallible, net::SocketAddr, sync::Arc};
+use std::{
+    convert::Infallible,
+    future::pending,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use crate::{
-    cache::Event,
-    config::CONFIG,
+    audit,
+    cache::Payload,
+    config::{LaggedClients, CONFIG},
     deserializer::{GatewayEvent, SequenceInfo},
-    model::{Identify, Resume},
+    dispatch::{self, RelayedEvent},
+    encoding::Encoding,
+    intents::ClientIntents,
+    model::{Identify, RequestGuildSync, Resume, SubscribeGuilds},
+    ratelimit::TokenBucket,
+    reconnect::CircuitState,
     state::{Session, Shard, State},
+    subscription::GuildSubscription,
     upgrade,
 };
 
-const HELLO: &str = r#"{"t":null,"s":null,"op":10,"d":{"heartbeat_interval":41250}}"#;
 const HEARTBEAT_ACK: &str = r#"{"t":null,"s":null,"op":11,"d":null}"#;
-const INVALID_SESSION: &str = r#"{"t":null,"s":null,"op":9,"d":false}"#;
+pub const INVALID_SESSION: &str = r#"{"t":null,"s":null,"op":9,"d":false}"#;
 const RESUMED: &str = r#"{"t":"RESUMED","s":null,"op":0,"d":{}}"#;
 
+/// Opcode of the proxy's own payload for subscribing to the events of some
+/// guilds, chosen to not collide with Discord's opcodes.
+const SUBSCRIBE_GUILDS: u8 = 200;
+
+/// Opcode of the proxy's own payload for getting a fresh GUILD_CREATE of a
+/// guild from the cache.
+const REQUEST_GUILD_SYNC: u8 = 201;
+
 const TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
 
+/// How long to wait for queued messages to be sent when a client disconnects.
+const SINK_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
 fn compress_full(compressor: &mut Compress, output: &mut Vec<u8>, input: &[u8]) {
     let before_in = compressor.total_in() as usize;
     while (compressor.total_in() as usize) - before_in < input.len() {
@@ -72,8 +109,10 @@ fn compress_full(compressor: &mut Compress, output: &mut Vec<u8>, input: &[u8])
 async fn sink_from_queue<S>(
     addr: SocketAddr,
     mut use_zlib: bool,
+    encoding: Encoding,
+    heartbeat_interval: u64,
     compress_rx: oneshot::Receiver<Option<bool>>,
-    mut message_stream: UnboundedReceiver<Message>,
+    mut message_stream: Receiver<Message>,
     mut sink: S,
 ) -> Result<(), Error>
 where
@@ -84,13 +123,21 @@ where
     let mut compression_buffer = Vec::with_capacity(32 * 1024);
 
     // At first, we will have to send a HELLO
+    let hello = format!(
+        r#"{{"t":null,"s":null,"op":10,"d":{{"heartbeat_interval":{heartbeat_interval}}}}}"#
+    );
+
+    let Some(hello) = encoding.encode(Message::Text(hello)) else {
+        return Ok(());
+    };
+
     if use_zlib {
-        compress_full(&mut compress, &mut compression_buffer, HELLO.as_bytes());
+        compress_full(&mut compress, &mut compression_buffer, &hello.into_data());
 
         sink.send(Message::Binary(compression_buffer.clone()))
             .await?;
     } else {
-        sink.send(Message::Text(HELLO.to_string())).await?;
+        sink.send(hello).await?;
     }
 
     if compress_rx.await == Ok(Some(true)) {
@@ -98,9 +145,19 @@ where
     }
 
     while let Some(msg) = message_stream.recv().await {
+        // Control frames are not compressed
+        let is_control = msg.is_close() || msg.is_ping();
+
         trace!("[{addr}] Sending {msg:?}");
 
-        if use_zlib {
+        let Some(msg) = encoding.encode(msg) else {
+            warn!("[{addr}] Failed to encode payload as {encoding}");
+            continue;
+        };
+
+        if is_control {
+            sink.send(msg).await?;
+        } else if use_zlib {
             compression_buffer.clear();
             compress_full(&mut compress, &mut compression_buffer, &msg.into_data());
 
@@ -114,67 +171,391 @@ where
     Ok(())
 }
 
+/// Relays the events of a shard to a client.
+struct Relay {
+    shard_id: u32,
+    shard_id_str: String,
+    stream_writer: Sender<Message>,
+    intents: ClientIntents,
+    guilds: Arc<GuildSubscription>,
+    encoding: Encoding,
+    use_zlib: bool,
+    /// Sequence number of the last event sent to the client.
+    seq: usize,
+    /// For formatting the sequence number as a string.
+    buffer: Buffer,
+    /// Time from the upgrade until the first event is relayed, only recorded
+    /// once.
+    connected_at: Option<Instant>,
+    /// Limits live events, if the rate limit is enabled.
+    bucket: Option<TokenBucket>,
+    grace_period: Duration,
+    /// When the client started being rate limited without a break.
+    limited_since: Option<Instant>,
+}
+
+impl Relay {
+    /// Wait until the rate limit allows sending another event, which fails
+    /// if the client has been rate limited for longer than the grace period.
+    async fn wait_for_rate_limit(&mut self) -> Result<(), &'static str> {
+        let Some(wait) = self.bucket.as_mut().and_then(TokenBucket::acquire) else {
+            self.limited_since = None;
+            return Ok(());
+        };
+
+        let limited_since = *self.limited_since.get_or_insert_with(Instant::now);
+
+        if limited_since.elapsed() > self.grace_period {
+            warn!(
+                "[Shard {}] Client has been rate limited for too long, disconnecting",
+                self.shard_id
+            );
+            metrics::increment_counter!("gateway_clients_rate_limited");
+
+            let _res = self
+                .stream_writer
+                .send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Policy,
+                    reason: "Too many events".into(),
+                })))
+                .await;
+
+            return Err("rate_limited");
+        }
+
+        sleep(wait).await;
+
+        Ok(())
+    }
+
+    /// Send an event to the client with its sequence number, unless it is
+    /// filtered out by the client's intents or guild subscription.
+    ///
+    /// Only live events are rate limited, since the client asked for the
+    /// replayed ones by resuming or falling behind.
+    async fn relay(
+        &mut self,
+        event: &RelayedEvent,
+        received_at: Option<Instant>,
+        rate_limited: bool,
+    ) -> Result<(), &'static str> {
+        let event_type = event.event_type();
+
+        // Skip events for intents the client did not declare or guilds it did not subscribe to
+        if !event_type.map_or(true, |event_type| {
+            self.intents.allows(event_type, event.guild_id) && self.guilds.allows(event.guild_id)
+        }) {
+            return Ok(());
+        }
+
+        if rate_limited {
+            self.wait_for_rate_limit().await?;
+        }
+
+        let payload = &event.payload;
+        let sequence = event
+            .sequence
+            .as_ref()
+            .map(|SequenceInfo(_, sequence_range)| {
+                self.seq += 1;
+
+                (self.seq, sequence_range)
+            });
+
+        // Clients that use ETF get a copy of the shared encoding with their sequence number
+        let etf = (self.encoding == Encoding::Etf)
+            .then(|| event.etf())
+            .flatten()
+            .and_then(|etf| etf.with_sequence(sequence.map(|(sequence, _)| sequence)));
+
+        let message = if let Some(etf) = etf {
+            Message::Binary(etf)
+        } else if let Some((sequence, sequence_range)) = sequence {
+            // Copy the shared payload with the sequence number overwritten
+            let sequence = self.buffer.format(sequence);
+
+            let mut copy =
+                String::with_capacity(payload.len() - sequence_range.len() + sequence.len());
+            copy.push_str(&payload[..sequence_range.start]);
+            copy.push_str(sequence);
+            copy.push_str(&payload[sequence_range.end..]);
+
+            Message::Text(copy)
+        } else {
+            Message::Text(payload.clone())
+        };
+
+        let span = trace_span!("send_event", error = field::Empty);
+        trace!(parent: &span, "[Shard {}] Relaying event to client", self.shard_id);
+
+        let len = message.len();
+
+        match self.stream_writer.send(message).await {
+            Ok(()) => {
+                metrics::counter!("gateway_proxy_bytes_sent_total", len as u64, "shard" => self.shard_id_str.clone());
+
+                if let Some(event_type) = event_type {
+                    metrics::increment_counter!(
+                        "gateway_proxy_events_sent",
+                        "shard" => self.shard_id_str.clone(),
+                        "event_type" => event_type.to_owned()
+                    );
+                }
+
+                // Replayed events were received too long ago for this to be meaningful
+                if let Some(received_at) = received_at {
+                    metrics::histogram!(
+                        "gateway_proxy_fanout_latency_seconds",
+                        received_at.elapsed().as_secs_f64(),
+                        "shard" => self.shard_id_str.clone()
+                    );
+                }
+
+                if let Some(connected_at) = self.connected_at.take() {
+                    let elapsed_ms = connected_at.elapsed().as_secs_f64() * 1000.0;
+
+                    metrics::histogram!(
+                        "gateway_client_time_to_first_event_ms",
+                        elapsed_ms,
+                        "encoding" => self.encoding.name(),
+                        "zlib" => if self.use_zlib { "true" } else { "false" }
+                    );
+                }
+
+                Ok(())
+            }
+            Err(e) => {
+                span.record("error", field::display(&e));
+
+                // The client disconnected
+                Err("closed")
+            }
+        }
+    }
+}
+
+/// Forward the events of a shard to a client until it stops by itself, which
+/// returns the reason the client is disconnected for.
+#[allow(clippy::too_many_arguments, clippy::too_many_lines)]
 async fn forward_shard(
     session_id: String,
     shard_status: Arc<Shard>,
-    stream_writer: UnboundedSender<Message>,
+    stream_writer: Sender<Message>,
     send_guilds: bool,
-    mut seq: usize,
-) {
+    seq: usize,
+    last_event: Arc<AtomicU64>,
+    connected_at: Instant,
+    use_zlib: bool,
+    encoding: Encoding,
+    intents: ClientIntents,
+    guilds: Arc<GuildSubscription>,
+    mut guild_sync: UnboundedReceiver<Id<GuildMarker>>,
+) -> &'static str {
     // Subscribe to events for this shard
-    let mut event_receiver = shard_status.events.subscribe();
+    let (mut event_receiver, last_index) = shard_status
+        .replay
+        .subscribe(|| shard_status.events.subscribe());
     let shard_id = shard_status.id;
 
     debug!("[Shard {shard_id}] Starting to send events to client",);
 
-    // Wait until we have a valid READY payload for this shard
-    let ready_payload = shard_status.ready.wait_until_ready().await;
+    // Everything up to here was either sent to the client before or happened before it
+    // identified, everything after will be received by the subscription
+    let replay = if send_guilds {
+        last_event.store(last_index, Ordering::Relaxed);
+        Vec::new()
+    } else {
+        shard_status
+            .replay
+            .events_after(last_event.load(Ordering::Relaxed))
+            .unwrap_or_else(|| {
+                warn!("[Shard {shard_id}] Events to replay were evicted while resuming");
+                Vec::new()
+            })
+    };
 
-    if send_guilds {
-        // Get a fake ready payload to send to the client
-        let mut ready_payload = shard_status
-            .guilds
-            .get_ready_payload(ready_payload, &mut seq);
+    // Clients of shards that stopped for good are disconnected, even while they wait for READY
+    let mut closed = shard_status.closed.subscribe();
 
-        // Overwrite the session ID in the READY
-        if let Event::Ready(payload) = &mut ready_payload.d {
-            payload.insert(String::from("session_id"), OwnedValue::String(session_id));
+    // Wait until we have a valid READY payload for this shard
+    let ready_payload = tokio::select! {
+        ready_payload = shard_status.ready.wait_until_ready() => ready_payload,
+        frame = fatally_closed(&mut closed) => {
+            let _res = stream_writer.send(Message::Close(Some(frame))).await;
+            return "shard_closed";
         }
+    };
 
-        if let Ok(serialized) = to_string(&ready_payload) {
+    let rate_limit = &CONFIG.client_rate_limit;
+    let mut relay = Relay {
+        shard_id,
+        shard_id_str: Buffer::new().format(shard_id).to_owned(),
+        stream_writer,
+        intents,
+        guilds,
+        encoding,
+        use_zlib,
+        seq,
+        buffer: Buffer::new(),
+        connected_at: Some(connected_at),
+        bucket: (rate_limit.events_per_second > 0)
+            .then(|| TokenBucket::new(rate_limit.events_per_second, Duration::from_secs(1))),
+        grace_period: Duration::from_secs(rate_limit.grace_period),
+        limited_since: None,
+    };
+
+    if send_guilds {
+        // Get a fake ready payload to send to the client
+        let ready_payload = shard_status.guilds.get_ready_payload(
+            ready_payload,
+            session_id,
+            &CONFIG.externally_accessible_url,
+            &mut relay.seq,
+        );
+
+        if let Some(serialized) = serialize(&ready_payload) {
             debug!("[Shard {shard_id}] Sending newly created READY");
-            let _res = stream_writer.send(Message::Text(serialized));
+            let _res = relay.stream_writer.send(Message::Text(serialized)).await;
         };
 
         // Send GUILD_CREATE/GUILD_DELETEs based on guild availability
-        for payload in shard_status.guilds.get_guild_payloads(&mut seq) {
-            if let Ok(serialized) = to_string(&payload) {
-                trace!(
-                    "[Shard {shard_id}] Sending newly created GUILD_CREATE/GUILD_DELETE payload",
-                );
-                let _res = stream_writer.send(Message::Text(serialized));
-            };
+        // Serialized up front, so that the cache is not locked while waiting for the client
+        let guild_payloads: Vec<String> = shard_status
+            .guilds
+            .get_guild_payloads(&mut relay.seq)
+            .filter_map(|payload| serialize(&payload))
+            .collect();
+
+        for serialized in guild_payloads {
+            trace!("[Shard {shard_id}] Sending newly created GUILD_CREATE/GUILD_DELETE payload",);
+            let _res = relay.stream_writer.send(Message::Text(serialized)).await;
         }
     } else {
-        let _res = stream_writer.send(Message::Text(RESUMED.to_string()));
+        let _res = relay
+            .stream_writer
+            .send(Message::Text(RESUMED.to_string()))
+            .await;
     }
 
-    // For formatting the sequence number as a string, reuse a buffer
-    let mut buffer = Buffer::new();
+    // Send the events the client missed while it was disconnected
+    if !replay.is_empty() {
+        debug!("[Shard {shard_id}] Replaying {} events", replay.len());
+    }
+
+    for (index, event) in replay {
+        if let Err(reason) = relay.relay(&event, None, false).await {
+            return reason;
+        }
+
+        last_event.store(index, Ordering::Relaxed);
+    }
 
     loop {
-        let res = event_receiver.recv().await;
+        let res = tokio::select! {
+            res = event_receiver.recv() => res,
+            frame = fatally_closed(&mut closed) => {
+                debug!("[Shard {shard_id}] Disconnecting client of closed shard");
+                let _res = relay.stream_writer.send(Message::Close(Some(frame))).await;
+                return "shard_closed";
+            }
+            Some(guild_id) = guild_sync.recv() => {
+                debug!("[Shard {shard_id}] Syncing guild {guild_id} to client");
+
+                if let Some(serialized) = shard_status
+                    .guilds
+                    .get_guild_payload(guild_id, &mut relay.seq)
+                    .and_then(|payload| serialize(&payload))
+                {
+                    let _res = relay.stream_writer.send(Message::Text(serialized)).await;
+                }
+
+                continue;
+            }
+        };
 
-        if let Ok((mut payload, sequence)) = res {
-            // Overwrite the sequence number
-            if let Some(SequenceInfo(_, sequence_range)) = sequence {
-                seq += 1;
-                payload.replace_range(sequence_range, buffer.format(seq));
+        if let Ok((event, span, index, received_at)) = res {
+            // Already sent before subscribing or replayed
+            if index <= last_event.load(Ordering::Relaxed) {
+                continue;
             }
 
-            let _res = stream_writer.send(Message::Text(payload));
+            // Continue the span the event was received in
+            let relayed = relay
+                .relay(&event, Some(received_at), true)
+                .instrument(span.unwrap_or_else(Span::none))
+                .await;
+
+            if let Err(reason) = relayed {
+                return reason;
+            }
+
+            last_event.store(index, Ordering::Relaxed);
         } else if let Err(RecvError::Lagged(amt)) = res {
             warn!("[Shard {shard_id}] Client is {amt} events behind!",);
+
+            if CONFIG.lagged_clients == LaggedClients::Disconnect {
+                metrics::increment_counter!("gateway_clients_lagged", "shard" => shard_id.to_string(), "action" => "disconnect");
+
+                let _res = relay
+                    .stream_writer
+                    .send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Policy,
+                        reason: "Too many events behind".into(),
+                    })))
+                    .await;
+
+                return "lagged";
+            }
+
+            metrics::increment_counter!("gateway_clients_lagged", "shard" => shard_id.to_string(), "action" => "catch_up");
+
+            // The receiver skipped the events, so get them from the replay buffer instead
+            if let Some(missed) = shard_status
+                .replay
+                .events_after(last_event.load(Ordering::Relaxed))
+            {
+                for (index, event) in missed {
+                    // These are live events the client fell behind on
+                    if let Err(reason) = relay.relay(&event, None, true).await {
+                        return reason;
+                    }
+
+                    last_event.store(index, Ordering::Relaxed);
+                }
+            } else {
+                warn!("[Shard {shard_id}] Skipping events that are not buffered anymore");
+                let _res = relay
+                    .stream_writer
+                    .send(Message::Text(RESUMED.to_string()))
+                    .await;
+            }
+        }
+    }
+}
+
+/// Serialize a payload built by the proxy without the fields configured in
+/// `strip_fields`, like the events relayed from Discord.
+fn serialize(payload: &Payload) -> Option<String> {
+    let serialized = to_string(payload).ok()?;
+
+    Some(dispatch::strip_fields(&serialized, &payload.t).unwrap_or(serialized))
+}
+
+/// Wait until Discord closed the connection of a shard for good, returning
+/// the close frame for its clients.
+async fn fatally_closed(
+    closed: &mut watch::Receiver<Option<CloseFrame<'static>>>,
+) -> CloseFrame<'static> {
+    loop {
+        let frame = closed.borrow_and_update().clone();
+
+        if let Some(frame) = frame {
+            return frame;
+        }
+
+        // The sender is kept by the shard, so this only fails once it is gone
+        if closed.changed().await.is_err() {
+            pending::<()>().await;
         }
     }
 }
@@ -185,7 +566,12 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
     stream: S,
     state: State,
     use_zlib: bool,
+    encoding: Encoding,
+    api_version: Option<u8>,
 ) -> Result<(), Error> {
+    // Used for measuring how long it takes until the client receives its first event
+    let connected_at = Instant::now();
+
     // We use a oneshot channel to tell the forwarding task whether the IDENTIFY
     // contained a compression request
     let (compress_tx, compress_rx) = oneshot::channel();
@@ -193,37 +579,144 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
 
     // We need to know which shard this client is connected to in order to send messages to it
     let mut shard_sender = None;
+    let mut shard_id_str: Option<String> = None;
+
+    // Guilds of the current session the client subscribed to
+    let mut guild_subscription = None;
+
+    // Guilds to send a fresh GUILD_CREATE of, sent by the forwarding task
+    let mut guild_sync = None;
 
-    let stream = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+    let config = WebSocketConfig {
+        max_message_size: Some(CONFIG.max_message_size),
+        max_frame_size: Some(CONFIG.max_frame_size),
+        ..WebSocketConfig::default()
+    };
+    let stream = WebSocketStream::from_raw_socket(stream, Role::Server, Some(config)).await;
 
     let (sink, mut stream) = stream.split();
 
-    // Write all messages from a queue to the sink
-    let (stream_writer, stream_receiver) = unbounded_channel::<Message>();
+    // Write all messages from a queue to the sink. Events wait for room in the queue, so that
+    // clients that can't keep up fall behind on the shard's events instead of filling it, and
+    // replies to the client are dropped if it stopped reading.
+    let (stream_writer, stream_receiver) = channel::<Message>(CONFIG.backpressure);
 
-    let sink_task = tokio::spawn(sink_from_queue(
+    let mut sink_task = tokio::spawn(sink_from_queue(
         addr,
         use_zlib,
+        encoding,
+        state.heartbeat_interval(),
         compress_rx,
         stream_receiver,
         sink,
     ));
 
-    let mut shard_forward_task = None;
+    let mut shard_forward_task: Option<JoinHandle<&'static str>> = None;
 
-    while let Some(Ok(msg)) = stream.next().await {
-        let data = msg.into_data();
-        #[cfg(feature = "simd-json")]
-        let mut payload = unsafe { String::from_utf8_unchecked(data) };
-        #[cfg(not(feature = "simd-json"))]
+    // Whether we told the client to disconnect because the proxy is shutting down
+    let mut closing = false;
+
+    // Detect dead connections with WebSocket pings, independent of heartbeats
+    let ping_period = Duration::from_secs(CONFIG.ping_interval);
+    let mut ping_interval = interval_at(Instant::now() + ping_period, ping_period);
+    let mut unanswered_pings = 0_u8;
+
+    // Label of the session duration metric
+    let mut disconnect_reason = "closed";
+
+    loop {
+        let msg = tokio::select! {
+            msg = stream.next() => msg,
+            _ = ping_interval.tick() => {
+                if unanswered_pings == 2 {
+                    warn!("[{addr}] Client did not answer pings, disconnecting");
+
+                    let _res = stream_writer.try_send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "Ping timeout".into(),
+                    })));
+                    disconnect_reason = "timeout";
+                    break;
+                }
+
+                unanswered_pings += 1;
+                let _res = stream_writer.try_send(Message::Ping(Vec::new()));
+                continue;
+            }
+            () = state.shutdown.cancelled(), if !closing => {
+                debug!("[{addr}] Shutting down, closing connection");
+                closing = true;
+
+                let _res = stream_writer.try_send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "Proxy is shutting down".into(),
+                })));
+
+                // Keep reading until the client acknowledged the close
+                continue;
+            }
+        };
+
+        let msg = match msg {
+            Some(Ok(msg)) => msg,
+            Some(Err(Error::Capacity(e))) => {
+                warn!("[{addr}] Client sent a payload that is too large: {e}");
+
+                let _res = stream_writer.try_send(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Size,
+                    reason: "Payload too large".into(),
+                })));
+                disconnect_reason = "invalid";
+                break;
+            }
+            Some(Err(e)) => {
+                debug!("[{addr}] Connection failed: {e}");
+                disconnect_reason = "error";
+                break;
+            }
+            None => {
+                if closing {
+                    disconnect_reason = "shutdown";
+                }
+
+                break;
+            }
+        };
+
+        if msg.is_pong() {
+            unanswered_pings = 0;
+            continue;
+        }
+
+        if let Some(shard_id_str) = &shard_id_str {
+            metrics::counter!("gateway_proxy_bytes_received_total", msg.len() as u64, "shard" => shard_id_str.clone());
+        }
+
+        let Some(data) = encoding.decode(msg.into_data()) else {
+            debug!("[{addr}] Received invalid {encoding} payload");
+            continue;
+        };
+
+        // Binary messages are not validated by tungstenite, so only trust clients if asked to
+        #[cfg(all(feature = "unsafe-decode", not(feature = "safe-decode")))]
         let payload = unsafe { String::from_utf8_unchecked(data) };
+        #[cfg(any(feature = "safe-decode", not(feature = "unsafe-decode")))]
+        let Ok(payload) = String::from_utf8(data) else {
+            debug!("[{addr}] Received payload that is not valid UTF-8");
+            metrics::increment_counter!("gateway_invalid_utf8_total");
+            continue;
+        };
+        #[cfg(feature = "simd-json")]
+        let mut payload = payload;
 
-        let Some(deserializer) = GatewayEvent::from_json(&payload) else { continue };
+        let Some(deserializer) = GatewayEvent::from_json(&payload) else {
+            continue;
+        };
 
         match deserializer.op() {
             1 => {
                 trace!("[{addr}] Sending heartbeat ACK");
-                let _res = stream_writer.send(Message::Text(HEARTBEAT_ACK.to_string()));
+                let _res = stream_writer.try_send(Message::Text(HEARTBEAT_ACK.to_string()));
             }
             2 => {
                 debug!("[{addr}] Client is identifying");
@@ -237,6 +730,7 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                     Ok(identify) => identify,
                     Err(e) => {
                         warn!("[{addr}] Invalid identify payload: {e:?}");
+                        audit::invalid_payload(addr, "identify");
                         continue;
                     }
                 };
@@ -245,42 +739,124 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
 
                 if shard_count != state.shard_count {
                     warn!("[{addr}] Shard count from client identify mismatched, disconnecting",);
+                    audit::identify(addr, &identify.d, Some("shard_count"));
+                    disconnect_reason = "invalid";
                     break;
                 }
 
-                if shard_id >= shard_count {
+                // Only a range of the shards may be run by this proxy
+                let Some(shard) = state.shard(shard_id) else {
                     warn!("[{addr}] Shard ID from client is out of range, disconnecting",);
+                    audit::identify(addr, &identify.d, Some("shard_id"));
+                    disconnect_reason = "invalid";
+                    break;
+                };
+
+                // The shard can't deliver any events while it's not reconnecting
+                if shard.info.read().unwrap().circuit == CircuitState::Open {
+                    warn!("[{addr}] Shard {shard_id} is waiting to reconnect, disconnecting");
+                    audit::identify(addr, &identify.d, Some("shard_unavailable"));
+
+                    let _res = stream_writer.try_send(Message::Close(Some(CloseFrame {
+                        code: CloseCode::Again,
+                        reason: "Shard is unavailable".into(),
+                    })));
+                    disconnect_reason = "invalid";
                     break;
                 }
 
                 // Discord tokens may be prefixed by 'Bot ' in IDENTIFY
                 if identify.d.token.split_whitespace().last() != Some(&CONFIG.token) {
                     warn!("[{addr}] Token from client mismatched, disconnecting");
+                    audit::identify(addr, &identify.d, Some("token"));
+                    disconnect_reason = "invalid";
                     break;
                 }
 
+                // Events are relayed as they are, so they are only valid for one API version
+                if let (Some(requested), Some(version)) = (api_version, shard.ready.api_version()) {
+                    if requested != version {
+                        warn!("[{addr}] Client requested API version {requested}, but the shards use {version}, disconnecting");
+                        audit::identify(addr, &identify.d, Some("api_version"));
+
+                        let _res =
+                            stream_writer.try_send(Message::text(INVALID_SESSION.to_string()));
+                        let _res = stream_writer.try_send(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Library(4012),
+                            reason: format!("Invalid API version, use v{version}").into(),
+                        })));
+                        disconnect_reason = "invalid";
+                        break;
+                    }
+                }
+
+                // Events for intents the shards did not identify with would never arrive
+                if let Some(intents) = identify.d.intents {
+                    if !CONFIG.intents.contains(intents) {
+                        let missing = intents.difference(CONFIG.intents);
+                        warn!("[{addr}] Client requested intents {missing:?} the proxy does not have, disconnecting");
+                        audit::identify(addr, &identify.d, Some("intents"));
+
+                        let _res =
+                            stream_writer.try_send(Message::text(INVALID_SESSION.to_string()));
+                        let _res = stream_writer.try_send(Message::Close(Some(CloseFrame {
+                            code: CloseCode::Library(4014),
+                            reason: "Disallowed intent(s).".into(),
+                        })));
+                        disconnect_reason = "invalid";
+                        break;
+                    }
+                }
+
                 trace!("[{addr}] Shard ID is {shard_id}");
+                audit::identify(addr, &identify.d, None);
+
+                // Clients without intents get all events the proxy receives
+                let intents = ClientIntents::new(identify.d.intents.unwrap_or_else(Intents::all));
 
                 // Create a new session for this client
+                let last_event = Arc::new(AtomicU64::new(0));
+                let guilds = Arc::new(GuildSubscription::default());
                 let session = Session {
                     shard_id,
                     compress: identify.d.compress,
+                    intents,
+                    last_event: last_event.clone(),
+                    guilds: guilds.clone(),
                 };
                 let session_id = state.create_session(session);
 
                 // The client is connected to this shard, so prepare for sending commands to it
-                let shard = state.shards[shard_id as usize].clone();
                 shard_sender = Some(shard.sender.clone());
+                shard_id_str = Some(shard_id.to_string());
 
-                if let Some(sender) = compress_tx.take() {
-                    shard_forward_task = Some(tokio::spawn(forward_shard(
-                        session_id,
-                        shard,
-                        stream_writer.clone(),
-                        true,
-                        0,
-                    )));
+                // Clients identify again on the same connection after an INVALID_SESSION
+                if let Some(shard_forward_task) = shard_forward_task.take() {
+                    debug!("[{addr}] Client is identifying again");
+                    shard_forward_task.abort();
+                }
+
+                let (sync_tx, sync_rx) = unbounded_channel();
+                guild_sync = Some(sync_tx);
+
+                shard_forward_task = Some(tokio::spawn(forward_shard(
+                    session_id,
+                    shard,
+                    stream_writer.clone(),
+                    true,
+                    0,
+                    last_event,
+                    connected_at,
+                    use_zlib,
+                    encoding,
+                    intents,
+                    guilds.clone(),
+                    sync_rx,
+                )));
+
+                guild_subscription = Some(guilds);
 
+                if let Some(sender) = compress_tx.take() {
                     // let _res = sender.send(identify.d.compress);
                     let _res = sender.send(Some(false));
                 }
@@ -297,6 +873,7 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                     Ok(resume) => resume,
                     Err(e) => {
                         warn!("[{addr}] Invalid resume payload: {e:?}");
+                        audit::invalid_payload(addr, "resume");
                         continue;
                     }
                 };
@@ -304,33 +881,124 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
                 // Discord tokens may be prefixed by 'Bot ' in RESUME
                 if resume.d.token.split_whitespace().last() != Some(&CONFIG.token) {
                     warn!("[{addr}] Token from client mismatched, disconnecting");
+                    audit::resume(addr, &resume.d, Some("token"));
+                    disconnect_reason = "invalid";
                     break;
                 }
 
                 // Find the shard that has the matching session ID
                 if let Some(session) = state.get_session(&resume.d.session_id) {
-                    let session_id = resume.d.session_id;
-                    debug!("[{addr}] Successfully resuming session {session_id}",);
+                    let session_id = &resume.d.session_id;
+                    // Sessions are only created for shards run by this proxy
+                    let shard = state.shard(session.shard_id).unwrap();
+
+                    // The client missed more events than can be replayed, so it has to identify
+                    if !shard
+                        .replay
+                        .can_replay_after(session.last_event.load(Ordering::Relaxed))
+                    {
+                        debug!("[{addr}] Session {session_id} expired");
+                        audit::resume(addr, &resume.d, Some("session_expired"));
+                        let _res =
+                            stream_writer.try_send(Message::text(INVALID_SESSION.to_string()));
+                        continue;
+                    }
 
-                    let shard = state.shards[session.shard_id as usize].clone();
+                    // Commands like REQUEST_GUILD_MEMBERS are sent to this shard as well
+                    shard_sender = Some(shard.sender.clone());
+                    shard_id_str = Some(session.shard_id.to_string());
 
                     if let Some(sender) = compress_tx.take() {
+                        debug!("[{addr}] Successfully resuming session {session_id}",);
+                        audit::resume(addr, &resume.d, None);
+
+                        let (sync_tx, sync_rx) = unbounded_channel();
+                        guild_sync = Some(sync_tx);
+
                         shard_forward_task = Some(tokio::spawn(forward_shard(
-                            session_id,
+                            session_id.clone(),
                             shard.clone(),
                             stream_writer.clone(),
                             false,
                             resume.d.seq,
+                            session.last_event,
+                            connected_at,
+                            use_zlib || session.compress == Some(true),
+                            encoding,
+                            session.intents,
+                            session.guilds.clone(),
+                            sync_rx,
                         )));
 
+                        guild_subscription = Some(session.guilds);
+
                         let _res = sender.send(session.compress);
                     } else {
-                        let _res = stream_writer.send(Message::text(INVALID_SESSION.to_string()));
+                        // Events of this connection are already forwarded from a shard
+                        audit::resume(addr, &resume.d, Some("already_connected"));
+                        let _res =
+                            stream_writer.try_send(Message::text(INVALID_SESSION.to_string()));
                     }
                 } else {
-                    let _res = stream_writer.send(Message::text(INVALID_SESSION.to_string()));
+                    audit::resume(addr, &resume.d, Some("session_not_found"));
+                    let _res = stream_writer.try_send(Message::text(INVALID_SESSION.to_string()));
                 }
             }
+            SUBSCRIBE_GUILDS => {
+                let Some(guilds) = &guild_subscription else {
+                    warn!("[{addr}] Client attempted to subscribe to guilds before IDENTIFY");
+                    continue;
+                };
+
+                #[cfg(feature = "simd-json")]
+                let maybe_subscribe = unsafe { simd_json::from_str(&mut payload) };
+                #[cfg(not(feature = "simd-json"))]
+                let maybe_subscribe = serde_json::from_str(&payload);
+
+                let subscribe: SubscribeGuilds = match maybe_subscribe {
+                    Ok(subscribe) => subscribe,
+                    Err(e) => {
+                        warn!("[{addr}] Invalid guild subscription payload: {e:?}");
+                        continue;
+                    }
+                };
+
+                debug!(
+                    "[{addr}] Client subscribed to guilds {:?}",
+                    subscribe.d.guild_ids
+                );
+                guilds.set(
+                    subscribe
+                        .d
+                        .guild_ids
+                        .map(|guild_ids| guild_ids.into_iter().collect()),
+                );
+            }
+            REQUEST_GUILD_SYNC => {
+                let Some(guild_sync) = &guild_sync else {
+                    warn!("[{addr}] Client attempted to sync a guild before IDENTIFY");
+                    continue;
+                };
+
+                #[cfg(feature = "simd-json")]
+                let maybe_sync = unsafe { simd_json::from_str(&mut payload) };
+                #[cfg(not(feature = "simd-json"))]
+                let maybe_sync = serde_json::from_str(&payload);
+
+                let sync: RequestGuildSync = match maybe_sync {
+                    Ok(sync) => sync,
+                    Err(e) => {
+                        warn!("[{addr}] Invalid guild sync payload: {e:?}");
+                        continue;
+                    }
+                };
+
+                debug!(
+                    "[{addr}] Client requested to sync guild {}",
+                    sync.d.guild_id
+                );
+                let _res = guild_sync.send(sync.d.guild_id);
+            }
             _ => {
                 if let Some(sender) = &shard_sender {
                     trace!("[{addr}] Sending {payload:?} to Discord directly");
@@ -344,15 +1012,41 @@ pub async fn handle_client<S: 'static + AsyncRead + AsyncWrite + Unpin + Send>(
 
     debug!("[{addr}] Client disconnected");
 
-    sink_task.abort();
-
     if let Some(shard_forward_task) = shard_forward_task {
-        shard_forward_task.abort();
+        // Forwarding stops by itself when the client fell too far behind or the shard closed
+        if shard_forward_task.is_finished() {
+            if let Ok(reason) = shard_forward_task.await {
+                disconnect_reason = reason;
+            }
+        } else {
+            shard_forward_task.abort();
+        }
+    }
+
+    metrics::histogram!(
+        "gateway_proxy_client_session_duration_seconds",
+        connected_at.elapsed().as_secs_f64(),
+        "reason" => disconnect_reason
+    );
+
+    // Give the sink a moment to send what is left, like close frames
+    drop(compress_tx);
+    drop(stream_writer);
+
+    if timeout(SINK_DRAIN_TIMEOUT, &mut sink_task).await.is_err() {
+        sink_task.abort();
     }
 
     Ok(())
 }
 
+/// Response body of the readiness probe.
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    not_ready_shards: Vec<u32>,
+}
+
 async fn handler(
     addr: SocketAddr,
     request: Request<Body>,
@@ -360,10 +1054,86 @@ async fn handler(
     metrics: Arc<PrometheusHandle>,
 ) -> Result<Response<Body>, Infallible> {
     let response = match (request.method(), request.uri().path()) {
-        (&Method::GET, "/metrics") => Response::builder()
+        // The metrics module shadows the metrics crate when imported
+        (&Method::GET, "/metrics") => crate::metrics::render(&metrics),
+        (&Method::GET, "/healthz") => Response::builder()
             .status(StatusCode::OK)
-            .body(Body::from(metrics.render()))
+            .body(Body::empty())
             .unwrap(),
+        (&Method::GET, "/readyz") => {
+            // Shards are ready once they received a READY and populated the cache
+            let not_ready_shards: Vec<u32> = state
+                .shards
+                .iter()
+                .filter(|shard| !shard.ready.is_ready())
+                .map(|shard| shard.id)
+                .collect();
+
+            let status = if not_ready_shards.is_empty() {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+
+            let readiness = Readiness {
+                ready: not_ready_shards.is_empty(),
+                not_ready_shards,
+            };
+
+            Response::builder()
+                .status(status)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(to_string(&readiness).unwrap_or_default()))
+                .unwrap()
+        }
+        (&Method::GET, "/stats") => {
+            let stats: Vec<_> = state
+                .shards
+                .iter()
+                .map(|shard| shard.guilds.cache_stats())
+                .collect();
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(to_string(&stats).unwrap_or_default()))
+                .unwrap()
+        }
+        (&Method::GET, "/shards") => {
+            let shards: Vec<_> = state
+                .shards
+                .iter()
+                .map(|shard| shard.info.read().unwrap().clone())
+                .collect();
+
+            Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(to_string(&shards).unwrap_or_default()))
+                .unwrap()
+        }
+        (&Method::GET, path) if path.starts_with("/shards/") => {
+            let shard = path
+                .trim_start_matches("/shards/")
+                .parse::<u32>()
+                .ok()
+                .and_then(|id| state.shard(id));
+
+            if let Some(shard) = shard {
+                let info = shard.info.read().unwrap().clone();
+
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(to_string(&info).unwrap_or_default()))
+                    .unwrap()
+            } else {
+                Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        }
         (&Method::GET, "/shard-count") => {
             let mut buffer = itoa::Buffer::new();
             let shard_count_str = buffer.format(state.shard_count);
@@ -388,6 +1158,13 @@ pub async fn run(
 ) -> Result<(), Error> {
     let addr: SocketAddr = ([0, 0, 0, 0], port).into();
 
+    #[cfg(feature = "tls")]
+    if let Some(acceptor) = crate::tls::acceptor()? {
+        return run_tls(addr, acceptor, state, metrics_handle).await;
+    }
+
+    let shutdown = state.shutdown.clone();
+
     let service = make_service_fn(move |addr: &AddrStream| {
         let state = state.clone();
         let metrics_handle = metrics_handle.clone();
@@ -402,7 +1179,15 @@ pub async fn run(
         }
     });
 
-    let server = Server::bind(&addr).serve(service);
+    let tcp = &CONFIG.tcp;
+    let server = Server::from_tcp(bind(addr)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?
+        .tcp_nodelay(tcp.nodelay)
+        .tcp_keepalive(tcp.keepalive_idle())
+        .tcp_keepalive_interval(tcp.keepalive_interval())
+        .tcp_keepalive_retries(tcp.keepalive_retries)
+        .serve(service)
+        .with_graceful_shutdown(async move { shutdown.cancelled().await });
 
     info!("Listening on {addr}");
 
@@ -412,3 +1197,117 @@ pub async fn run(
 
     Ok(())
 }
+
+/// Bind the listening socket, with `SO_REUSEPORT` if enabled so that several
+/// processes can listen on the same port and the OS balances connections
+/// between them.
+fn bind(addr: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+
+    socket.set_reuse_address(true)?;
+
+    #[cfg(unix)]
+    socket.set_reuse_port(CONFIG.tcp.reuse_port)?;
+
+    #[cfg(not(unix))]
+    if CONFIG.tcp.reuse_port {
+        warn!("tcp.reuse_port is only supported on Unix, ignoring it");
+    }
+
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+
+    Ok(socket.into())
+}
+
+/// Apply the configured socket options, which hyper does for the connections
+/// it accepts itself.
+#[cfg(feature = "tls")]
+fn configure_socket(stream: &tokio::net::TcpStream) -> std::io::Result<()> {
+    let tcp = &CONFIG.tcp;
+    stream.set_nodelay(tcp.nodelay)?;
+
+    if let Some(idle) = tcp.keepalive_idle() {
+        let mut keepalive = socket2::TcpKeepalive::new().with_time(idle);
+
+        if let Some(interval) = tcp.keepalive_interval() {
+            keepalive = keepalive.with_interval(interval);
+        }
+
+        if let Some(retries) = tcp.keepalive_retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+
+        socket2::SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+
+    Ok(())
+}
+
+/// Serve clients over TLS.
+///
+/// hyper's server can't wrap connections in TLS, so connections are accepted
+/// and served one by one instead.
+#[cfg(feature = "tls")]
+async fn run_tls(
+    addr: SocketAddr,
+    acceptor: tokio_rustls::TlsAcceptor,
+    state: State,
+    metrics_handle: Arc<PrometheusHandle>,
+) -> Result<(), Error> {
+    let listener = tokio::net::TcpListener::from_std(bind(addr)?)?;
+
+    info!("Listening on {addr} with TLS");
+
+    loop {
+        let (stream, addr) = tokio::select! {
+            res = listener.accept() => match res {
+                Ok(connection) => connection,
+                Err(e) => {
+                    warn!("Failed to accept connection: {e}");
+                    continue;
+                }
+            },
+            () = state.shutdown.cancelled() => break,
+        };
+
+        trace!("[{addr:?}] New connection");
+
+        if let Err(e) = configure_socket(&stream) {
+            warn!("[{addr}] Failed to set socket options: {e}");
+        }
+
+        let acceptor = acceptor.clone();
+        let state = state.clone();
+        let metrics_handle = metrics_handle.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    debug!("[{addr}] TLS handshake failed: {e}");
+                    return;
+                }
+            };
+
+            let service = service_fn(move |incoming: Request<Body>| {
+                handler(addr, incoming, state.clone(), metrics_handle.clone())
+            });
+
+            if let Err(e) = hyper::server::conn::Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                debug!("[{addr}] Connection error: {e}");
+            }
+        });
+    }
+
+    Ok(())
+}
@@ -1,13 +1,34 @@
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use tokio::sync::{broadcast, Notify};
+use serde::Serialize;
+#[cfg(feature = "simd-json")]
+use simd_json::ValueAccess;
+use tokio::sync::{broadcast, watch, Notify};
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_util::sync::CancellationToken;
 use twilight_gateway::MessageSender;
 
 use std::{
-    collections::HashMap,
-    sync::{Arc, RwLock},
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
 };
 
-use crate::{cache, dispatch::BroadcastMessage, model::JsonObject};
+use crate::{
+    cache,
+    config::CONFIG,
+    dispatch::{BroadcastMessage, RelayedEvent},
+    intents::ClientIntents,
+    model::JsonObject,
+    ratelimit::ConnectionLimiter,
+    reconnect::CircuitState,
+    subscription::GuildSubscription,
+};
+
+/// Heartbeat interval Discord usually sends, for clients connecting before any
+/// shard received a HELLO.
+const DEFAULT_HEARTBEAT_INTERVAL: u64 = 41250;
 
 /// Manager for the READY state of a shard.
 pub struct Ready {
@@ -41,6 +62,14 @@ impl Ready {
         self.changed.notify_waiters();
     }
 
+    /// Gateway API version from the `v` field of the READY.
+    pub fn api_version(&self) -> Option<u8> {
+        let inner = self.inner.read().unwrap();
+        let version = inner.as_ref()?.get("v")?.as_u64()?;
+
+        u8::try_from(version).ok()
+    }
+
     pub async fn wait_until_ready(&self) -> JsonObject {
         while !self.is_ready() {
             self.wait_changed().await;
@@ -50,6 +79,134 @@ impl Ready {
     }
 }
 
+/// An event kept in the [`ReplayBuffer`], alongside its index.
+pub type ReplayedEvent = (u64, Arc<RelayedEvent>);
+
+struct ReplayInner {
+    /// Index of the most recently relayed event.
+    last_index: u64,
+    events: VecDeque<ReplayedEvent>,
+}
+
+/// Buffer of the most recent events relayed for a shard, which are replayed
+/// to clients that resume their session.
+///
+/// Events are numbered by an index that increases for every relayed event,
+/// independently from the sequence numbers seen by clients.
+pub struct ReplayBuffer {
+    inner: RwLock<ReplayInner>,
+    capacity: usize,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: RwLock::new(ReplayInner {
+                last_index: 0,
+                events: VecDeque::with_capacity(capacity),
+            }),
+            capacity,
+        }
+    }
+
+    /// Add an event to the buffer and send it to clients with `send`, which
+    /// gets its index.
+    ///
+    /// Clients can't [`subscribe`](Self::subscribe) in between, so they either
+    /// receive the event or know that it happened before they subscribed.
+    pub fn push<T>(&self, event: Arc<RelayedEvent>, send: impl FnOnce(u64) -> T) -> T {
+        let mut inner = self.inner.write().unwrap();
+        inner.last_index += 1;
+        let index = inner.last_index;
+
+        if self.capacity > 0 {
+            if inner.events.len() == self.capacity {
+                inner.events.pop_front();
+            }
+
+            inner.events.push_back((index, event));
+        }
+
+        send(index)
+    }
+
+    /// Subscribe to the events of the shard with `subscribe` and return the
+    /// subscription alongside the index of the most recently relayed event,
+    /// which is the last one the subscription does not receive.
+    pub fn subscribe<T>(&self, subscribe: impl FnOnce() -> T) -> (T, u64) {
+        let inner = self.inner.read().unwrap();
+
+        (subscribe(), inner.last_index)
+    }
+
+    /// Whether all events after the given index are still buffered.
+    ///
+    /// If the buffer is disabled, there is nothing to replay and resuming is
+    /// always possible.
+    pub fn can_replay_after(&self, index: u64) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+
+        let inner = self.inner.read().unwrap();
+        let oldest = inner
+            .events
+            .front()
+            .map_or(inner.last_index + 1, |(oldest, _)| *oldest);
+
+        index + 1 >= oldest
+    }
+
+    /// All buffered events after the given index, or [`None`] if some of them
+    /// were already evicted from the buffer.
+    pub fn events_after(&self, index: u64) -> Option<Vec<ReplayedEvent>> {
+        if !self.can_replay_after(index) {
+            return None;
+        }
+
+        Some(
+            self.inner
+                .read()
+                .unwrap()
+                .events
+                .iter()
+                .filter(|(event_index, _)| *event_index > index)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+/// Connection details of a shard, updated periodically.
+#[derive(Clone, Serialize)]
+pub struct ShardInfo {
+    /// ID of the shard.
+    pub id: u32,
+    /// Connection status of the shard.
+    pub status: &'static str,
+    /// Most recent heartbeat latency in milliseconds.
+    pub latency_ms: Option<f64>,
+    /// ID of the shard's session with Discord.
+    pub session_id: Option<String>,
+    /// State of the shard's circuit breaker.
+    pub circuit: CircuitState,
+    /// Gateway API version from the shard's READY.
+    pub api_version: Option<u8>,
+}
+
+impl ShardInfo {
+    pub const fn new(id: u32) -> Self {
+        Self {
+            id,
+            status: "disconnected",
+            latency_ms: None,
+            session_id: None,
+            circuit: CircuitState::Closed,
+            api_version: None,
+        }
+    }
+}
+
 /// State of a single shard.
 pub struct Shard {
     /// ID of this shard.
@@ -62,6 +219,16 @@ pub struct Shard {
     pub ready: Ready,
     /// Cache for guilds on this shard.
     pub guilds: cache::Guilds,
+    /// Connection details of this shard.
+    pub info: RwLock<ShardInfo>,
+    /// Recent events of this shard for resuming clients.
+    pub replay: ReplayBuffer,
+    /// Heartbeat interval in milliseconds from Discord's last HELLO, 0 if
+    /// none was received yet.
+    pub heartbeat_interval: AtomicU64,
+    /// Close frame for the clients of this shard, set once Discord closed its
+    /// connection with a code that does not allow reconnecting.
+    pub closed: watch::Sender<Option<CloseFrame<'static>>>,
 }
 
 /// A session initiated by a client.
@@ -71,6 +238,13 @@ pub struct Session {
     pub shard_id: u32,
     /// Compression as requested in IDENTIFY.
     pub compress: Option<bool>,
+    /// Intents as declared in IDENTIFY.
+    pub intents: ClientIntents,
+    /// Index in the [`ReplayBuffer`] of the last event handled for this
+    /// session.
+    pub last_event: Arc<AtomicU64>,
+    /// Guilds the client subscribed to.
+    pub guilds: Arc<GuildSubscription>,
 }
 
 /// Global state for all shards managed by the proxy.
@@ -81,9 +255,39 @@ pub struct Inner {
     pub shard_count: u32,
     /// All sessions active in the proxy.
     pub sessions: RwLock<HashMap<String, Session>>,
+    /// Amount of clients currently connected.
+    pub clients: AtomicUsize,
+    /// Notified whenever a client disconnects.
+    pub client_disconnected: Notify,
+    /// Cancelled when the proxy is shutting down.
+    pub shutdown: CancellationToken,
+    /// Connection attempts per IP.
+    pub connection_limiter: ConnectionLimiter,
 }
 
 impl Inner {
+    /// Heartbeat interval in milliseconds to send to clients in HELLO.
+    ///
+    /// Discord sends the same interval to all shards, so this is taken from
+    /// whichever shard received one.
+    pub fn heartbeat_interval(&self) -> u64 {
+        CONFIG.heartbeat_interval.unwrap_or_else(|| {
+            self.shards
+                .iter()
+                .map(|shard| shard.heartbeat_interval.load(Ordering::Relaxed))
+                .find(|interval| *interval > 0)
+                .unwrap_or(DEFAULT_HEARTBEAT_INTERVAL)
+        })
+    }
+
+    /// Get a shard by its ID, if it is run by this proxy.
+    pub fn shard(&self, shard_id: u32) -> Option<Arc<Shard>> {
+        self.shards
+            .iter()
+            .find(|shard| shard.id == shard_id)
+            .cloned()
+    }
+
     /// Get a session by its ID.
     pub fn get_session(&self, session_id: &str) -> Option<Session> {
         self.sessions.read().unwrap().get(session_id).cloned()
@@ -106,7 +310,135 @@ impl Inner {
 
         session_id
     }
+
+    /// Count a client as connected until the returned guard is dropped, unless
+    /// the maximum amount of clients is already connected.
+    pub fn connect_client(self: &Arc<Self>, max_clients: Option<usize>) -> Option<ClientGuard> {
+        let connected = self
+            .clients
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |clients| {
+                max_clients
+                    .map_or(true, |max_clients| clients < max_clients)
+                    .then_some(clients + 1)
+            })
+            .ok()?;
+
+        metrics::gauge!("gateway_clients_connected", (connected + 1) as f64);
+
+        Some(ClientGuard(self.clone()))
+    }
+
+    /// Wait until all clients have disconnected.
+    pub async fn wait_for_clients(&self) {
+        loop {
+            // Register for notifications before checking to not miss any
+            let disconnected = self.client_disconnected.notified();
+
+            if self.clients.load(Ordering::Relaxed) == 0 {
+                return;
+            }
+
+            disconnected.await;
+        }
+    }
+}
+
+/// Marks a client as connected for as long as it is alive.
+pub struct ClientGuard(State);
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        let connected = self.0.clients.fetch_sub(1, Ordering::Relaxed) - 1;
+        self.0.client_disconnected.notify_waiters();
+
+        metrics::gauge!("gateway_clients_connected", connected as f64);
+    }
 }
 
 /// A reference to the [`StateInner`] of the proxy.
 pub type State = Arc<Inner>;
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast;
+
+    use std::sync::Arc;
+
+    use super::ReplayBuffer;
+    use crate::dispatch::RelayedEvent;
+
+    fn event() -> Arc<RelayedEvent> {
+        Arc::new(RelayedEvent::new(
+            String::from(r#"{"t":"TYPING_START","s":1,"op":0,"d":{}}"#),
+            None,
+        ))
+    }
+
+    /// A buffer with room for `capacity` events that saw `count` of them.
+    fn filled(capacity: usize, count: u64) -> ReplayBuffer {
+        let buffer = ReplayBuffer::new(capacity);
+
+        for _ in 0..count {
+            buffer.push(event(), |_| ());
+        }
+
+        buffer
+    }
+
+    fn indices(buffer: &ReplayBuffer, after: u64) -> Option<Vec<u64>> {
+        buffer
+            .events_after(after)
+            .map(|events| events.into_iter().map(|(index, _)| index).collect())
+    }
+
+    #[test]
+    fn push() {
+        let buffer = ReplayBuffer::new(2);
+
+        assert_eq!(buffer.push(event(), |index| index), 1);
+        assert_eq!(buffer.push(event(), |index| index), 2);
+        assert_eq!(buffer.push(event(), |index| index), 3);
+    }
+
+    #[test]
+    fn eviction() {
+        let buffer = filled(3, 5);
+
+        // Events 1 and 2 were evicted
+        assert_eq!(indices(&buffer, 0), None);
+        assert_eq!(indices(&buffer, 1), None);
+        assert_eq!(indices(&buffer, 2), Some(vec![3, 4, 5]));
+        assert_eq!(indices(&buffer, 4), Some(vec![5]));
+        assert_eq!(indices(&buffer, 5), Some(vec![]));
+    }
+
+    #[test]
+    fn can_replay_after() {
+        let buffer = filled(3, 5);
+
+        assert!(!buffer.can_replay_after(0));
+        assert!(!buffer.can_replay_after(1));
+        assert!(buffer.can_replay_after(2));
+        assert!(buffer.can_replay_after(5));
+
+        // Nothing was relayed yet
+        assert!(ReplayBuffer::new(3).can_replay_after(0));
+
+        // There is nothing to replay without a buffer
+        let disabled = filled(0, 5);
+        assert!(disabled.can_replay_after(0));
+        assert_eq!(indices(&disabled, 0), Some(vec![]));
+    }
+
+    #[test]
+    fn subscribe() {
+        let buffer = filled(3, 2);
+        let (sender, _) = broadcast::channel(3);
+
+        let (mut receiver, last_index) = buffer.subscribe(|| sender.subscribe());
+        assert_eq!(last_index, 2);
+
+        buffer.push(event(), |index| sender.send(index).unwrap());
+        assert_eq!(receiver.try_recv(), Ok(3));
+    }
+}
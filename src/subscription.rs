@@ -0,0 +1,28 @@
+use twilight_model::id::{marker::GuildMarker, Id};
+
+use std::{collections::HashSet, sync::RwLock};
+
+/// Guilds a client subscribed to, if it only wants the events of some guilds.
+///
+/// Clients subscribe with the proxy's own opcode after identifying, events
+/// that don't belong to a guild are always sent.
+#[derive(Default)]
+pub struct GuildSubscription(RwLock<Option<HashSet<Id<GuildMarker>>>>);
+
+impl GuildSubscription {
+    /// Only send events of these guilds, or of all guilds if [`None`].
+    pub fn set(&self, guild_ids: Option<HashSet<Id<GuildMarker>>>) {
+        *self.0.write().unwrap() = guild_ids;
+    }
+
+    /// Whether an event of this guild may be sent to the client.
+    pub fn allows(&self, guild_id: Option<Id<GuildMarker>>) -> bool {
+        let guild_ids = self.0.read().unwrap();
+
+        let Some(guild_ids) = guild_ids.as_ref() else {
+            return true;
+        };
+
+        guild_id.map_or(true, |guild_id| guild_ids.contains(&guild_id))
+    }
+}
@@ -0,0 +1,45 @@
+use rustls_pemfile::Item;
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+
+use std::{
+    fs::File,
+    io::{BufReader, Error, ErrorKind, Result},
+    sync::Arc,
+};
+
+use crate::config::CONFIG;
+
+/// Load the certificate chain and private key from the PEM files at
+/// `tls_cert_path` and `tls_key_path`.
+///
+/// Returns [`None`] if either of them is not set, in which case clients
+/// connect without TLS.
+pub fn acceptor() -> Result<Option<TlsAcceptor>> {
+    let (Some(cert_path), Some(key_path)) = (&CONFIG.tls_cert_path, &CONFIG.tls_key_path) else {
+        return Ok(None);
+    };
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    let key = rustls_pemfile::read_all(&mut BufReader::new(File::open(key_path)?))?
+        .into_iter()
+        .find_map(|item| match item {
+            Item::RSAKey(key) | Item::PKCS8Key(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "No private key found"))?;
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+
+    Ok(Some(TlsAcceptor::from(Arc::new(config))))
+}
@@ -1,36 +1,94 @@
 use base64::{engine::general_purpose::STANDARD, Engine};
 use hyper::{
     header::{
-        HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION,
+        HeaderMap, HeaderValue, AUTHORIZATION, CONNECTION, CONTENT_TYPE, RETRY_AFTER,
+        SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION,
         UPGRADE,
     },
     http::StatusCode,
     upgrade, Body, Request, Response,
 };
-use ring::digest;
-use tracing::error;
+use ring::{constant_time::verify_slices_are_equal, digest};
+use tracing::{error, info_span, warn, Instrument};
 
 use std::net::SocketAddr;
 
-use crate::{server::handle_client, state::State};
+use crate::{audit, config::CONFIG, encoding::Encoding, server::handle_client, state::State};
 
 /// Websocket GUID constant as specified in RFC6455:
 /// <https://datatracker.ietf.org/doc/html/rfc6455#section-1.3>
 const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 
+const UNAUTHORIZED: &str = r#"{"message":"401: Unauthorized","code":0}"#;
+
+/// Subprotocols that some clients request, none of them change how the proxy
+/// communicates.
+const SUBPROTOCOLS: [&str; 2] = ["discord", "gateway"];
+
+/// Subprotocols offered in the Sec-WebSocket-Protocol headers, in order of
+/// preference.
+fn offered_subprotocols(headers: &HeaderMap) -> Vec<&str> {
+    headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|subprotocol| !subprotocol.is_empty())
+        .collect()
+}
+
+/// Check the token from the `Authorization` header or the `token` query
+/// string parameter against the configured token hashes.
+fn is_authorized(addr: SocketAddr, headers: &HeaderMap, query: Option<&str>) -> bool {
+    if CONFIG.auth.tokens.is_empty() {
+        return true;
+    }
+
+    let header_token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.strip_prefix("Bearer ").unwrap_or(value));
+    let query_token = query.and_then(|q| q.split('&').find_map(|pair| pair.strip_prefix("token=")));
+
+    let Some(token) = header_token.or(query_token) else {
+        audit::authentication(addr, None, false);
+        return false;
+    };
+
+    let hex = audit::hash_token(token);
+
+    let authorized = CONFIG.auth.tokens.iter().any(|allowed| {
+        verify_slices_are_equal(allowed.to_ascii_lowercase().as_bytes(), hex.as_bytes()).is_ok()
+    });
+    audit::authentication(addr, Some(token), authorized);
+
+    authorized
+}
+
 /// Accept a websocket upgrade request and start processing the client's
 /// events afterwards.
 ///
 /// This method is one of two parts in the communication between server
-/// and client where zlib-stream compression may be requested.
+/// and client where zlib-stream compression may be requested. It is also
+/// where the client picks its encoding.
 pub async fn server(addr: SocketAddr, mut request: Request<Body>, state: State) -> Response<Body> {
     let uri = request.uri();
-    // let query = uri.query();
+    let query = uri.query();
 
     // Track whether the client requested zlib encoding in the query
     // string parameters
-    // let use_zlib = query.map_or(false, |q| q.contains("compress=zlib-stream"));
-    let use_zlib = false;
+    let use_zlib = query.map_or(false, |q| q.contains("compress=zlib-stream"));
+
+    // Clients may request ETF instead of JSON
+    let encoding = Encoding::from_query(query);
+
+    // Checked against the API version of the shard when identifying
+    let api_version = query.and_then(|q| {
+        q.split('&')
+            .find_map(|pair| pair.strip_prefix("v="))
+            .and_then(|version| version.parse().ok())
+    });
 
     let mut response = Response::new(Body::empty());
 
@@ -39,6 +97,54 @@ pub async fn server(addr: SocketAddr, mut request: Request<Body>, state: State)
         return response;
     }
 
+    // Clients without a subprotocol are fine, but ones that require an unknown one are not
+    let offered_subprotocols = offered_subprotocols(request.headers());
+    let subprotocol = offered_subprotocols
+        .iter()
+        .find_map(|offered| SUBPROTOCOLS.into_iter().find(|known| known == offered));
+
+    if subprotocol.is_none() && !offered_subprotocols.is_empty() {
+        warn!("[{addr}] Client requested unknown subprotocols {offered_subprotocols:?}");
+
+        *response.status_mut() = StatusCode::BAD_REQUEST;
+        return response;
+    }
+
+    if let Err(retry_after) = state.connection_limiter.check(addr.ip()) {
+        warn!("[{addr}] Client is connecting too often, rejecting client");
+
+        *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(retry_after.as_secs().max(1)));
+
+        return response;
+    }
+
+    if !is_authorized(addr, request.headers(), query) {
+        warn!("[{addr}] Client failed to authenticate");
+
+        *response.status_mut() = StatusCode::UNAUTHORIZED;
+        *response.body_mut() = Body::from(UNAUTHORIZED);
+        response
+            .headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+
+        return response;
+    }
+
+    // Counts the client as connected until it disconnects
+    let Some(client_guard) = state.connect_client(CONFIG.max_clients) else {
+        warn!("[{addr}] Maximum amount of clients connected, rejecting client");
+
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from_static("5"));
+
+        return response;
+    };
+
     if let Some(websocket_key) = request.headers().get(SEC_WEBSOCKET_KEY) {
         let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
         ctx.update(websocket_key.as_bytes());
@@ -50,10 +156,15 @@ pub async fn server(addr: SocketAddr, mut request: Request<Body>, state: State)
         tokio::spawn(async move {
             match upgrade::on(&mut request).await {
                 Ok(upgraded) => {
-                    let _res = handle_client(addr, upgraded, state, use_zlib).await;
+                    let _res =
+                        handle_client(addr, upgraded, state, use_zlib, encoding, api_version)
+                            .instrument(info_span!("client", client_addr = %addr))
+                            .await;
                 }
                 Err(e) => error!("[{}] Websocket upgrade error: {}", addr, e),
             }
+
+            drop(client_guard);
         });
 
         *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
@@ -70,6 +181,13 @@ pub async fn server(addr: SocketAddr, mut request: Request<Body>, state: State)
         response
             .headers_mut()
             .insert(SEC_WEBSOCKET_VERSION, HeaderValue::from_static("13"));
+
+        if let Some(subprotocol) = subprotocol {
+            response.headers_mut().insert(
+                SEC_WEBSOCKET_PROTOCOL,
+                HeaderValue::from_static(subprotocol),
+            );
+        }
     } else {
         *response.status_mut() = StatusCode::BAD_REQUEST;
     }